@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::csv::Row;
+use ::csv::ReaderBuilder;
+use derive_more::{Display, Error};
+
+#[derive(Debug, Display, Error)]
+#[display(
+    fmt = "failed to read baseline report '{}': {}",
+    "path.display()",
+    source
+)]
+pub struct BaselineError {
+    path: PathBuf,
+    source: ::csv::Error,
+}
+
+/// Identifies the same comparison across two reports
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct BaselineKey {
+    repo: Option<String>,
+    pr_number: Option<String>,
+    git_sha: String,
+    compared: Option<String>,
+}
+
+impl BaselineKey {
+    fn new(row: &Row) -> Self {
+        BaselineKey {
+            repo: row.repo.clone(),
+            pr_number: row.pr_number.clone(),
+            git_sha: row.git_sha.clone(),
+            compared: row.compared.clone(),
+        }
+    }
+}
+
+/// Elapsed times of a previous report, in seconds, to compare new rows with
+pub struct Baseline(HashMap<BaselineKey, (Option<i64>, Option<i64>)>);
+
+impl Baseline {
+    /// Reads a tsv report with every column and elapsed times in seconds,
+    /// i.e. one written without --columns or --time-unit
+    pub fn read(path: &Path, delimiter: u8) -> Result<Self, BaselineError> {
+        let error = |source| BaselineError {
+            path: path.to_path_buf(),
+            source,
+        };
+        let mut reader = ReaderBuilder::new()
+            .delimiter(delimiter)
+            .from_path(path)
+            .map_err(error)?;
+        let mut elapsed_times = HashMap::new();
+        for row in reader.deserialize() {
+            let row: Row = row.map_err(error)?;
+            elapsed_times.insert(
+                BaselineKey::new(&row),
+                (
+                    row.drone1_unit_test_elapsed_time,
+                    row.drone2_total_elapsed_time,
+                ),
+            );
+        }
+        Ok(Baseline(elapsed_times))
+    }
+
+    /// Fills in the baseline deltas of `row`, leaving them empty when the
+    /// baseline has no matching row or either side has no elapsed time
+    pub fn apply(&self, row: &mut Row) {
+        let baseline = self.0.get(&BaselineKey::new(row));
+        let delta = |elapsed_time: Option<i64>, baseline_elapsed_time: Option<i64>| {
+            Some(elapsed_time? - baseline_elapsed_time?)
+        };
+        row.baseline_delta_drone1_unit_test_elapsed_time = Some(
+            baseline.and_then(|baseline| delta(row.drone1_unit_test_elapsed_time, baseline.0)),
+        );
+        row.baseline_delta_drone2_total_elapsed_time =
+            Some(baseline.and_then(|baseline| delta(row.drone2_total_elapsed_time, baseline.1)));
+    }
+}
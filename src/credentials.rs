@@ -0,0 +1,116 @@
+use derive_more::{Display, Error};
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Display, Error)]
+pub enum CredentialsError {
+    #[display(
+        fmt = "failed to read credentials file '{}': {}",
+        "path.display()",
+        source
+    )]
+    Read { path: PathBuf, source: io::Error },
+    #[display(
+        fmt = "failed to parse credentials file '{}': {}",
+        "path.display()",
+        source
+    )]
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[display(
+        fmt = "failed to parse credentials file '{}': {}",
+        "path.display()",
+        source
+    )]
+    Toml {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[display(fmt = "credentials file '{}' is missing '{}'", "path.display()", key)]
+    MissingKey {
+        path: PathBuf,
+        #[error(not(source))]
+        key: &'static str,
+    },
+    #[display(
+        fmt = "no {} given; set {}, or use --credentials-file or --config",
+        key,
+        env
+    )]
+    Missing {
+        #[error(not(source))]
+        key: &'static str,
+        env: String,
+    },
+    #[display(fmt = "{} is empty", key)]
+    Empty {
+        #[error(not(source))]
+        key: &'static str,
+    },
+}
+
+/// Drone tokens read from a `.json` or `.toml` file, so they don't have to be
+/// passed on the command line
+#[derive(Debug, Default, Deserialize)]
+pub struct Credentials {
+    pub drone1_token: Option<String>,
+    pub drone2_token: Option<String>,
+}
+
+impl Credentials {
+    /// Files ending in `.toml` are parsed as TOML, anything else as JSON
+    pub fn from_file(path: &Path) -> Result<Self, CredentialsError> {
+        let contents = fs::read_to_string(path).map_err(|source| CredentialsError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            toml::from_str(&contents).map_err(|source| CredentialsError::Toml {
+                path: path.to_path_buf(),
+                source,
+            })
+        } else {
+            serde_json::from_str(&contents).map_err(|source| CredentialsError::Json {
+                path: path.to_path_buf(),
+                source,
+            })
+        }
+    }
+}
+
+/// Picks the token given on the command line or environment, falling back to
+/// the value of `key` read from the credentials file at `path`; a blank
+/// token is an error rather than a 401 on the first request
+pub fn resolve_token(
+    token: Option<&str>,
+    credentials_file: Option<(&Path, Option<&str>)>,
+    key: &'static str,
+    env: &str,
+) -> Result<String, CredentialsError> {
+    let token = match (token, credentials_file) {
+        (Some(token), _) | (None, Some((_, Some(token)))) => token,
+        (None, Some((path, None))) => {
+            return Err(CredentialsError::MissingKey {
+                path: path.to_path_buf(),
+                key,
+            })
+        }
+        (None, None) => {
+            return Err(CredentialsError::Missing {
+                key,
+                env: env.to_string(),
+            })
+        }
+    };
+    if token.trim().is_empty() {
+        return Err(CredentialsError::Empty { key });
+    }
+    Ok(token.to_string())
+}
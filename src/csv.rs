@@ -1,153 +1,1531 @@
-use std::io;
-use std::{io::Write, path::PathBuf};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
 
-use crate::drone::{wallet_platform_system_status, DroneBuildInfo, DroneStatus};
+use crate::drone::{
+    wallet_platform_system_status, BuildMode, CommitBuildMap, DroneBuildInfo, DroneStage,
+    DroneStatus, DroneStep, DroneTime,
+};
+use crate::output::{OutputSink, OutputTarget};
 use ::csv::WriterBuilder;
-use serde::Serialize;
-use std::collections::HashMap;
+use clap::ValueEnum;
+use derive_more::{Display, Error};
+use log::{debug, warn};
+use regex::Regex;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
 use url::Url;
 
 // Report should include                                                                                                                                                                                   (Await-finish - Drone2-start)
 // PR_Number | PR_URL| Git_Sha | Drone1_Build_Number | Drone2_Build_Number | Drone1_Unit_Test_Status | Drone1_Await_Test_Status | Drone2_Notify_Test_Status | Drone1_Unit_Test_Elapsed_Time | Drone2_System_Elapsed_Time + Await_Status_Complete | Await_Within_Three_Minutes_Of_Unit_Test_Start | Delta_Await_Status_Finished_To_Drone1_Unit_Test_Start
 //    u32     String    String           u32                     u32                DroneStatus                DroneStatus                 DroneStatus                      u32 (sec)                              u32 (sec)                                                         bool                                        u32 (sec)
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Row {
-    pub pr_number: String,
+    /// Only present when comparing several repositories
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repo: Option<String>,
+    /// Empty when the build's link isn't a pull request link
+    pub pr_number: Option<String>,
     pub pr_url: Url,
+    /// The sha of the Drone1 build; the Drone2 build may have built another
+    /// when correlating by pull request
     pub git_sha: String,
     pub drone1_build_number: u32,
-    pub drone2_build_number: u32,
+    /// This and the other Drone2 columns are empty when only Drone1 builds
+    /// are reported, see `ReportConfig::drone1_only`
+    pub drone2_build_number: Option<u32>,
     pub drone1_unit_test_status: DroneStatus,
     pub drone1_await_test_status: DroneStatus,
-    pub drone2_system_status: DroneStatus,
-    pub drone1_unit_test_elapsed_time: i64,
-    pub drone2_total_elapsed_time: i64,
-    pub await_within_three_minutes_of_unit_test_start: bool,
-    pub delta_await_complete_to_unit_test_start: i64,
-    pub await_faster_than_unit_test: bool,
-    pub delta_await_complete_to_unit_test_complete: i64,
+    pub drone2_system_status: Option<DroneStatus>,
+    /// This and the following timings are empty for rows whose unit test
+    /// step was skipped, see `ReportConfig::include_skipped`
+    pub drone1_unit_test_elapsed_time: Option<i64>,
+    pub drone2_total_elapsed_time: Option<i64>,
+    /// Whether the await step finished within `ReportConfig::await_threshold_secs`
+    /// (five minutes by default, whatever the column's name says) of the unit
+    /// test step starting
+    pub await_within_three_minutes_of_unit_test_start: Option<bool>,
+    pub delta_await_complete_to_unit_test_start: Option<i64>,
+    pub await_faster_than_unit_test: Option<bool>,
+    pub delta_await_complete_to_unit_test_complete: Option<i64>,
+    /// Negative when Drone2 started after the await step
+    pub delta_drone2_start_to_await_start: Option<i64>,
+    /// Whether the Drone1 unit test status and the Drone2 system status
+    /// agree, i.e. are both success or both anything else, such as a failure
+    /// or a skipped unit test step
+    #[serde(default)]
+    pub statuses_agree: Option<bool>,
+    /// Whether the Drone2 build started more than
+    /// `ReportConfig::skew_tolerance_secs` after the Drone1 await step, which
+    /// waits on it, had finished, or more than that before the Drone1 build,
+    /// which the same push triggered, was created. Only the clocks of the two
+    /// instances disagreeing, one way or the other, explains either, and the
+    /// cross-instance timings of such a row can't be trusted
+    #[serde(default)]
+    pub clock_skew_suspected: Option<bool>,
+    /// The `stage:step` pair this row compares; only present when pairs are
+    /// configured explicitly
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compared: Option<String>,
+    /// This run's Drone1 unit test elapsed time minus the baseline's; only
+    /// present when comparing against a baseline, and empty for rows
+    /// missing from it
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub baseline_delta_drone1_unit_test_elapsed_time: Option<Option<i64>>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub baseline_delta_drone2_total_elapsed_time: Option<Option<i64>>,
+    /// The last lines of the log of a failed Drone1 await step; only present
+    /// with --with-error-tail, and empty for rows whose await step didn't
+    /// fail
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub drone1_await_error_tail: Option<Option<String>>,
+    /// What triggered the Drone1 build, e.g. `@hook` or a user's login; this
+    /// and `drone2_sender` are only written when selected with `columns`
+    #[serde(default)]
+    pub drone1_trigger: String,
+    #[serde(default)]
+    pub drone2_sender: Option<String>,
+    #[serde(skip)]
+    pub drone1_unit_test_step: StepLocation,
+    #[serde(skip)]
+    pub drone1_await_test_step: StepLocation,
 }
 
-pub fn write_csv(
-    commit_build_map: HashMap<String, (Vec<DroneBuildInfo>, Vec<DroneBuildInfo>)>,
-    output: Option<PathBuf>,
-    develop: bool,
-) {
-    if let Some(file_name) = output {
-        write_csv_aux(
-            commit_build_map,
-            WriterBuilder::new()
-                .delimiter(b'\t')
-                .from_path(file_name)
-                .unwrap(),
-            develop,
-        );
-    } else {
-        write_csv_aux(
-            commit_build_map,
-            WriterBuilder::new()
-                .delimiter(b'\t')
-                .from_writer(io::stdout().lock()),
-            develop,
-        );
+impl Row {
+    /// Applies `DroneStatus::normalize` to every status column
+    pub fn normalize_statuses(&mut self) {
+        self.drone1_unit_test_status = self.drone1_unit_test_status.normalize();
+        self.drone1_await_test_status = self.drone1_await_test_status.normalize();
+        self.drone2_system_status = self.drone2_system_status.map(DroneStatus::normalize);
     }
 }
 
-fn write_csv_aux<W: Write>(
-    commit_build_map: HashMap<String, (Vec<DroneBuildInfo>, Vec<DroneBuildInfo>)>,
-    mut csv_writer: csv::Writer<W>,
-    develop: bool,
-) {
-    for (git_sha, (mut drone1_builds, mut drone2_builds)) in commit_build_map {
-        // if there aren't builds to compare, continue
-        if drone1_builds.is_empty() || drone2_builds.is_empty() {
-            continue;
+/// Identifies a step within a build, e.g. to fetch its logs later
+#[derive(Debug, Clone, Default)]
+pub struct StepLocation {
+    pub build_number: u32,
+    pub stage_number: u32,
+    pub step_number: u32,
+    pub step_name: String,
+}
+
+impl StepLocation {
+    fn new(build_number: u32, stage: &DroneStage, step: &DroneStep) -> Self {
+        StepLocation {
+            build_number,
+            stage_number: stage.get_number(),
+            step_number: step.get_number(),
+            step_name: step.get_name().to_string(),
         }
+    }
+}
 
-        let stage_name = if develop {
-            "build-develop"
-        } else {
-            "build-pull-request"
+#[derive(Debug, Display, Error)]
+#[display(fmt = "invalid step pair '{}', expected 'stage:step'", _0)]
+pub struct InvalidStepPair(#[error(not(source))] String);
+
+/// A Drone1 stage and the step within it to compare, in `stage:step` form
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepPair {
+    pub stage: String,
+    pub step: String,
+}
+
+impl FromStr for StepPair {
+    type Err = InvalidStepPair;
+
+    fn from_str(pair: &str) -> Result<Self, Self::Err> {
+        match pair.split_once(':') {
+            Some((stage, step)) if !stage.is_empty() && !step.is_empty() => Ok(StepPair {
+                stage: stage.to_string(),
+                step: step.to_string(),
+            }),
+            _ => Err(InvalidStepPair(pair.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for StepPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.stage, self.step)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Tab-separated values with a header row
+    Tsv,
+    /// A single JSON array of rows
+    Json,
+    /// One JSON object per line
+    Ndjson,
+}
+
+/// A column of the report, named like the `Row` field it holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[clap(rename_all = "snake_case")]
+pub enum Column {
+    Repo,
+    PrNumber,
+    PrUrl,
+    GitSha,
+    Drone1BuildNumber,
+    Drone2BuildNumber,
+    Drone1UnitTestStatus,
+    Drone1AwaitTestStatus,
+    Drone2SystemStatus,
+    Drone1UnitTestElapsedTime,
+    Drone2TotalElapsedTime,
+    AwaitWithinThreeMinutesOfUnitTestStart,
+    DeltaAwaitCompleteToUnitTestStart,
+    AwaitFasterThanUnitTest,
+    DeltaAwaitCompleteToUnitTestComplete,
+    DeltaDrone2StartToAwaitStart,
+    StatusesAgree,
+    ClockSkewSuspected,
+    Compared,
+    BaselineDeltaDrone1UnitTestElapsedTime,
+    BaselineDeltaDrone2TotalElapsedTime,
+    Drone1AwaitErrorTail,
+    Drone1Trigger,
+    Drone2Sender,
+}
+
+impl Column {
+    pub fn name(self) -> &'static str {
+        self.to_possible_value()
+            .expect("no column is skipped")
+            .get_name()
+    }
+
+    /// Whether the column is about the Drone2 build, and so empty when only
+    /// Drone1 builds are reported
+    pub fn is_drone2(self) -> bool {
+        matches!(
+            self,
+            Column::Drone2BuildNumber
+                | Column::Drone2SystemStatus
+                | Column::Drone2TotalElapsedTime
+                | Column::DeltaDrone2StartToAwaitStart
+                | Column::StatusesAgree
+                | Column::ClockSkewSuspected
+                | Column::BaselineDeltaDrone2TotalElapsedTime
+                | Column::Drone2Sender
+        )
+    }
+
+    /// Whether the column is only written when selected explicitly, keeping
+    /// the default report the same
+    pub fn is_opt_in(self) -> bool {
+        matches!(self, Column::Drone1Trigger | Column::Drone2Sender)
+    }
+}
+
+/// The columns of a `Row` holding a build status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[clap(rename_all = "snake_case")]
+pub enum StatusColumn {
+    Drone1UnitTestStatus,
+    Drone1AwaitTestStatus,
+    Drone2SystemStatus,
+}
+
+impl StatusColumn {
+    /// `None` for the Drone2 column of a row without a Drone2 build
+    pub fn status(self, row: &Row) -> Option<DroneStatus> {
+        match self {
+            StatusColumn::Drone1UnitTestStatus => Some(row.drone1_unit_test_status),
+            StatusColumn::Drone1AwaitTestStatus => Some(row.drone1_await_test_status),
+            StatusColumn::Drone2SystemStatus => row.drone2_system_status,
+        }
+    }
+}
+
+/// How the elapsed-time columns are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TimeUnit {
+    Seconds,
+    /// Drone timestamps only have a granularity of a second, so these are
+    /// always whole seconds
+    Millis,
+    /// e.g. `1m 23s`
+    Human,
+}
+
+impl TimeUnit {
+    fn format(self, seconds: i64) -> Value {
+        match self {
+            TimeUnit::Seconds => Value::from(seconds),
+            TimeUnit::Millis => Value::from(seconds.saturating_mul(1000)),
+            TimeUnit::Human => Value::from(format_elapsed_time(seconds)),
+        }
+    }
+}
+
+/// Formats seconds as hours, minutes and seconds, leaving out leading units
+/// that are zero, e.g. `1h 0m 5s`, `1m 23s` or `45s`
+pub fn format_elapsed_time(seconds: i64) -> String {
+    let sign = if seconds < 0 { "-" } else { "" };
+    let seconds = seconds.unsigned_abs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{sign}{seconds}s"),
+        (0, _) => format!("{sign}{minutes}m {seconds}s"),
+        _ => format!("{sign}{hours}h {minutes}m {seconds}s"),
+    }
+}
+
+/// A row as written to the report: its columns in order, with elapsed times
+/// in the configured unit
+struct ReportRow(Vec<(&'static str, Value)>);
+
+impl ReportRow {
+    /// `columns` of `None` selects every column but the opt-in ones, leaving
+    /// out `repo`, `compared`, the baseline deltas and the error tail when
+    /// they're `None`
+    /// like serializing `row` itself does, and the Drone2 columns when
+    /// `drone1_only`
+    fn new(row: &Row, columns: Option<&[Column]>, drone1_only: bool, time_unit: TimeUnit) -> Self {
+        let Value::Object(mut fields) = serde_json::to_value(row).unwrap() else {
+            unreachable!("rows serialize to JSON objects");
+        };
+        for column in [
+            Column::Drone1UnitTestElapsedTime,
+            Column::Drone2TotalElapsedTime,
+        ] {
+            if let Some(seconds) = fields.get(column.name()).and_then(Value::as_i64) {
+                fields.insert(column.name().to_string(), time_unit.format(seconds));
+            }
+        }
+        let row = match columns {
+            Some(columns) => columns
+                .iter()
+                .map(|column| {
+                    let value = fields.get(column.name()).cloned().unwrap_or(Value::Null);
+                    (column.name(), value)
+                })
+                .collect(),
+            None => Column::value_variants()
+                .iter()
+                .filter(|column| !column.is_opt_in())
+                .filter(|column| !(drone1_only && column.is_drone2()))
+                .filter_map(|column| Some((column.name(), fields.remove(column.name())?)))
+                .collect(),
+        };
+        ReportRow(row)
+    }
+
+    fn header(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|(name, _)| *name)
+    }
+
+    /// Fields of a tsv record, with missing values left empty
+    fn record(&self) -> Vec<String> {
+        self.0
+            .iter()
+            .map(|(_, value)| match value {
+                Value::Null => String::new(),
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            })
+            .collect()
+    }
+}
+
+impl Serialize for ReportRow {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, value) in &self.0 {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Which of a commit's builds on one Drone instance is compared. The build is
+/// picked from Drone1 and Drone2 independently, so a commit built twice on
+/// Drone1 and once on Drone2 still produces a single pairing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PickStrategy {
+    /// The lowest-numbered build
+    First,
+    /// The highest-numbered build, e.g. the last rerun
+    Last,
+    /// The highest-numbered successful build; commits without one on either
+    /// instance are left out
+    LatestSuccess,
+}
+
+impl PickStrategy {
+    /// `builds` must be ordered by build number
+    fn pick(self, builds: &[DroneBuildInfo]) -> Option<&DroneBuildInfo> {
+        match self {
+            PickStrategy::First => builds.first(),
+            PickStrategy::Last => builds.last(),
+            PickStrategy::LatestSuccess => builds
+                .iter()
+                .rev()
+                .find(|build| build.build_info.status == DroneStatus::Success),
+        }
+    }
+}
+
+/// Settings controlling how builds are compared and turned into `Row`s
+#[derive(Debug, Clone)]
+pub struct ReportConfig {
+    pub mode: BuildMode,
+    pub await_threshold_secs: i64,
+    /// Compare how far the await step finished from the unit test step
+    /// starting in either direction, so an await step finishing well before
+    /// the unit test step started, e.g. through clock skew, isn't counted as
+    /// within the threshold
+    pub await_abs: bool,
+    /// Seconds the Drone2 build may seem to start after the Drone1 await
+    /// step finished before clock skew is suspected
+    pub skew_tolerance_secs: i64,
+    /// Drone1 stage holding the unit test and await steps for pull requests
+    pub pr_stage: String,
+    /// Drone1 stage holding the unit test and await steps for develop pushes
+    pub develop_stage: String,
+    /// Drone1 stage holding the unit test and await steps for tags
+    pub tag_stage: String,
+    pub unit_test_step: String,
+    pub await_step: String,
+    /// Drone1 stage and unit test step pairs to compare, each producing its
+    /// own row; when empty, the stage picked by `mode` and
+    /// `unit_test_step` are compared
+    pub compare: Vec<StepPair>,
+    /// Keep rows whose unit test step was skipped, without timings, instead
+    /// of dropping them
+    pub include_skipped: bool,
+    /// Fold the statuses of every row with `DroneStatus::normalize` before
+    /// they're matched against `statuses`
+    pub normalize_status: bool,
+    /// Which build of each commit is compared when an instance built it more
+    /// than once
+    pub pick: PickStrategy,
+    /// Drone2 stages whose combined status is reported as the system status
+    pub system_stage_regex: Regex,
+    /// Only consider stages of this pipeline type, e.g. `kubernetes`, when
+    /// looking up steps and the system status
+    pub stage_type: Option<String>,
+    /// Report Drone1 builds on their own, leaving the Drone2 columns empty,
+    /// for when there's no Drone2 instance to compare with
+    pub drone1_only: bool,
+    /// Only keep rows whose statuses are all among these; empty keeps every
+    /// row
+    pub statuses: Vec<DroneStatus>,
+}
+
+impl ReportConfig {
+    fn statuses_match(&self, row: &Row) -> bool {
+        self.statuses.is_empty()
+            || [
+                Some(row.drone1_unit_test_status),
+                Some(row.drone1_await_test_status),
+                row.drone2_system_status,
+            ]
+            .iter()
+            .flatten()
+            .all(|status| self.statuses.contains(status))
+    }
+}
+
+/// Why a comparison was left out of the report
+#[derive(Debug, Clone, Copy)]
+enum DropReason {
+    NoBuilds,
+    NoPickedBuild,
+    MissingStage,
+    MissingStep,
+    Skipped,
+    Unfinished,
+    NoSystemStatus,
+    StatusFiltered,
+}
+
+/// How many comparisons, one per commit and compared step, `build_rows` left
+/// out of the report and why; a stage name that matches nothing shows up here
+/// rather than as a quietly empty report
+#[derive(Debug, Default, Serialize)]
+pub struct DropCounts {
+    pub considered: usize,
+    pub no_builds: usize,
+    pub no_picked_build: usize,
+    pub missing_stage: usize,
+    pub missing_step: usize,
+    pub skipped: usize,
+    pub unfinished: usize,
+    pub no_system_status: usize,
+    pub status_filtered: usize,
+    /// Builds left out before any comparison for not having the shape of
+    /// their instance's builds, see `DroneClient::skipped_builds`
+    pub unexpected_shape: usize,
+}
+
+impl DropCounts {
+    fn record(&mut self, reason: DropReason, count: usize) {
+        let counter = match reason {
+            DropReason::NoBuilds => &mut self.no_builds,
+            DropReason::NoPickedBuild => &mut self.no_picked_build,
+            DropReason::MissingStage => &mut self.missing_stage,
+            DropReason::MissingStep => &mut self.missing_step,
+            DropReason::Skipped => &mut self.skipped,
+            DropReason::Unfinished => &mut self.unfinished,
+            DropReason::NoSystemStatus => &mut self.no_system_status,
+            DropReason::StatusFiltered => &mut self.status_filtered,
         };
+        *counter += count;
+    }
+
+    fn reasons(&self) -> [(usize, &'static str); 8] {
+        [
+            (self.no_builds, "without builds on both instances"),
+            (self.no_picked_build, "without a build to pick"),
+            (self.missing_stage, "missing the stage"),
+            (self.missing_step, "missing a step"),
+            (self.skipped, "with the unit test step skipped"),
+            (self.unfinished, "with unfinished steps"),
+            (self.no_system_status, "without a system status"),
+            (self.status_filtered, "filtered out by status"),
+        ]
+    }
 
-        // order builds by build number
-        drone1_builds.sort_by(|a, b| a.build_info.number.cmp(&b.build_info.number));
-        drone2_builds.sort_by(|a, b| a.build_info.number.cmp(&b.build_info.number));
-
-        let drone1_build = &drone1_builds[0];
-        let drone2_build = &drone2_builds[0];
-
-        let pr_number = drone1_build.get_pr_number();
-        let pr_url = drone2_build.get_pr_url();
-        let drone1_build_number = drone1_build.build_info.number;
-        let drone2_build_number = drone2_build.build_info.number;
-        let drone1_stage = drone1_build.get_stage(stage_name);
-        let drone1_stage = match drone1_stage {
-            Some(stage) => stage,
-            None => {
-                println!("No stage '{stage_name}' in drone1 build '{drone1_build_number}'");
-                continue;
+    pub fn dropped(&self) -> usize {
+        self.reasons().iter().map(|(count, _)| count).sum()
+    }
+
+    /// Whether any comparison or build was left out
+    pub fn any(&self) -> bool {
+        self.dropped() > 0 || self.unexpected_shape > 0
+    }
+}
+
+impl fmt::Display for DropCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dropped = self.dropped();
+        write!(f, "dropped {dropped} of {} comparisons", self.considered)?;
+        if self.considered > 0 {
+            write!(
+                f,
+                " ({:.1}%)",
+                dropped as f64 * 100.0 / self.considered as f64
+            )?;
+        }
+        let mut separator = ": ";
+        for (count, reason) in self.reasons() {
+            if count > 0 {
+                write!(f, "{separator}{count} {reason}")?;
+                separator = ", ";
             }
+        }
+        if self.unexpected_shape > 0 {
+            write!(
+                f,
+                "; skipped {} builds not shaped like their instance's",
+                self.unexpected_shape
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the number of rows written
+pub fn write_csv(
+    rows: impl IntoIterator<Item = Row>,
+    output: Option<OutputTarget>,
+    format: OutputFormat,
+    delimiter: u8,
+) -> io::Result<usize> {
+    let mut report_writer = ReportWriter::new(output, format, delimiter)?;
+    for row in rows {
+        report_writer.write_row(&row);
+    }
+    report_writer.finish()
+}
+
+/// Writes rows one at a time as they become available, so a long run produces
+/// output as it goes and a failure late in the run keeps what was written
+pub struct ReportWriter {
+    format: ReportFormat,
+    /// `None` writes every column of `Row`
+    columns: Option<Vec<Column>>,
+    drone1_only: bool,
+    time_unit: TimeUnit,
+    /// Whether the TSV header goes before the first row
+    header: bool,
+    written: usize,
+}
+
+enum ReportFormat {
+    Tsv(Box<::csv::Writer<Box<dyn OutputSink>>>),
+    Json(Box<dyn OutputSink>),
+    Ndjson(Box<dyn OutputSink>),
+}
+
+/// `output`, or stdout when it's `None`; `Send`, so a report can be finished
+/// from the Ctrl-C handler's thread
+fn open_output(output: Option<OutputTarget>) -> io::Result<Box<dyn OutputSink>> {
+    match output {
+        Some(output) => output.open(),
+        None => Ok(Box::new(io::stdout())),
+    }
+}
+
+impl ReportWriter {
+    /// Writes to `output`, or to stdout when it's `None`
+    pub fn new(
+        output: Option<OutputTarget>,
+        format: OutputFormat,
+        delimiter: u8,
+    ) -> io::Result<Self> {
+        Ok(Self::from_writer(open_output(output)?, format, delimiter))
+    }
+
+    /// Appends to the file at `output`, creating it if needed. The TSV
+    /// header is only written into an empty file, so the rows of later runs
+    /// fall under the header of the first, which only lines up when every
+    /// run writes the same columns. Each run appends a JSON report as an
+    /// array of its own, so a rolling file is better kept in TSV or NDJSON.
+    pub fn append(output: PathBuf, format: OutputFormat, delimiter: u8) -> io::Result<Self> {
+        let open = || -> io::Result<(File, bool)> {
+            let file = OpenOptions::new().create(true).append(true).open(&output)?;
+            let empty = file.metadata()?.len() == 0;
+            Ok((file, empty))
+        };
+        let (file, empty) = open().map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!("failed to open '{}': {error}", output.display()),
+            )
+        })?;
+        let mut report_writer =
+            Self::from_writer(Box::new(BufWriter::new(file)), format, delimiter);
+        report_writer.header = empty;
+        Ok(report_writer)
+    }
+
+    fn from_writer(writer: Box<dyn OutputSink>, format: OutputFormat, delimiter: u8) -> Self {
+        let format = match format {
+            OutputFormat::Tsv => ReportFormat::Tsv(Box::new(
+                WriterBuilder::new()
+                    .delimiter(delimiter)
+                    .from_writer(writer),
+            )),
+            OutputFormat::Json => ReportFormat::Json(writer),
+            OutputFormat::Ndjson => ReportFormat::Ndjson(writer),
         };
-        let drone1_unit_test_step = match drone1_stage.get_step("run-wallet-platform-unit-tests") {
-            Some(step) => step,
-            None => {
-                println!(
-                    "No step 'run-wallet-platform-unit-tests' in drone1 build '{drone1_build_number}'"
-                );
-                continue;
+        ReportWriter {
+            format,
+            columns: None,
+            drone1_only: false,
+            time_unit: TimeUnit::Seconds,
+            header: true,
+            written: 0,
+        }
+    }
+
+    /// Writes only `columns`, in the given order
+    pub fn columns(mut self, columns: Vec<Column>) -> Self {
+        self.columns = Some(columns);
+        self
+    }
+
+    /// Leaves out the Drone2 columns, unless they're selected by `columns`
+    pub fn drone1_only(mut self) -> Self {
+        self.drone1_only = true;
+        self
+    }
+
+    /// Unit of the elapsed-time columns; seconds by default
+    pub fn time_unit(mut self, time_unit: TimeUnit) -> Self {
+        self.time_unit = time_unit;
+        self
+    }
+
+    pub fn write_row(&mut self, row: &Row) {
+        let row = ReportRow::new(
+            row,
+            self.columns.as_deref(),
+            self.drone1_only,
+            self.time_unit,
+        );
+        match &mut self.format {
+            // the csv crate can't serialize maps, so the header is written
+            // by hand
+            ReportFormat::Tsv(csv_writer) => {
+                if self.written == 0 && self.header {
+                    csv_writer.write_record(row.header()).unwrap();
+                }
+                csv_writer.write_record(row.record()).unwrap();
+            }
+            ReportFormat::Json(writer) => {
+                // the same layout as pretty-printing the whole array at once
+                write!(writer, "{}", if self.written == 0 { "[\n" } else { ",\n" }).unwrap();
+                let row = serde_json::to_string_pretty(&row).unwrap();
+                let row: Vec<String> = row.lines().map(|line| format!("  {line}")).collect();
+                write!(writer, "{}", row.join("\n")).unwrap();
             }
+            ReportFormat::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, &row).unwrap();
+                writeln!(writer).unwrap();
+            }
+        }
+        self.written += 1;
+    }
+
+    /// Pushes the rows written so far out to the file or stdout
+    pub fn flush(&mut self) {
+        match &mut self.format {
+            ReportFormat::Tsv(csv_writer) => csv_writer.flush().unwrap(),
+            ReportFormat::Json(writer) | ReportFormat::Ndjson(writer) => writer.flush().unwrap(),
+        }
+    }
+
+    /// Completes the report, e.g. uploading it, and returns the number of
+    /// rows written
+    pub fn finish(mut self) -> io::Result<usize> {
+        if let ReportFormat::Json(writer) = &mut self.format {
+            match self.written {
+                0 => writeln!(writer, "[]")?,
+                _ => writeln!(writer, "\n]")?,
+            }
+        }
+        let mut writer = match self.format {
+            ReportFormat::Tsv(csv_writer) => csv_writer
+                .into_inner()
+                .map_err(|error| io::Error::new(error.error().kind(), error.error().to_string()))?,
+            ReportFormat::Json(writer) | ReportFormat::Ndjson(writer) => writer,
         };
-        if drone1_unit_test_step.get_status() == DroneStatus::Skipped {
-            continue;
-        }
-        let drone1_await_test_step = match drone1_stage
-            .get_step("await-wallet-platform-test-status")
-        {
-            Some(step) => step,
-            None => {
-                println!(
-                    "No step 'await-wallet-platform-test-status' in drone1 build '{drone1_build_number}'"
-                );
-                continue;
+        writer.finish()?;
+        Ok(self.written)
+    }
+}
+
+/// Build-level timings of a commit, for `--compact` reports that skip the
+/// build details and so know nothing about stages and steps
+#[derive(Debug, Serialize)]
+pub struct CompactRow {
+    /// Only present when comparing several repositories
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repo: Option<String>,
+    pub pr_number: Option<String>,
+    pub pr_url: Url,
+    pub git_sha: String,
+    pub drone1_build_number: u32,
+    /// This and the other Drone2 columns are empty when only Drone1 builds
+    /// are reported
+    pub drone2_build_number: Option<u32>,
+    pub drone1_build_status: DroneStatus,
+    pub drone2_build_status: Option<DroneStatus>,
+    /// Empty for builds missing their started or finished timestamp
+    pub drone1_build_elapsed_time: Option<i64>,
+    pub drone2_build_elapsed_time: Option<i64>,
+}
+
+impl CompactRow {
+    /// Applies `DroneStatus::normalize` to both status columns
+    pub fn normalize_statuses(&mut self) {
+        self.drone1_build_status = self.drone1_build_status.normalize();
+        self.drone2_build_status = self.drone2_build_status.map(DroneStatus::normalize);
+    }
+}
+
+/// Timings of one step of a build
+#[derive(Debug, Serialize)]
+pub struct StepRow {
+    pub stage: String,
+    pub step: String,
+    pub status: DroneStatus,
+    /// This and the following timings are empty for steps that never started
+    /// or haven't finished
+    pub started: Option<DroneTime>,
+    pub stopped: Option<DroneTime>,
+    pub elapsed_time: Option<i64>,
+}
+
+/// A row for every step of every stage of `build`, in the order Drone lists
+/// them
+pub fn step_rows(build: &DroneBuildInfo) -> Vec<StepRow> {
+    build
+        .stages
+        .iter()
+        .flat_map(|stage| {
+            stage.get_steps().iter().map(|step| StepRow {
+                stage: stage.get_name().to_string(),
+                step: step.get_name().to_string(),
+                status: step.get_status(),
+                started: step.get_started_timestamp(),
+                stopped: step.get_stopped_timestamp(),
+                elapsed_time: step.elapsed_time(),
+            })
+        })
+        .collect()
+}
+
+/// Writes `step_rows` in the same formats as the comparison report
+pub fn write_step_rows(
+    step_rows: &[StepRow],
+    output: Option<OutputTarget>,
+    format: OutputFormat,
+    delimiter: u8,
+) -> io::Result<()> {
+    write_rows(step_rows, output, format, delimiter)
+}
+
+/// Writes `compact_rows` in the same formats as the comparison report
+pub fn write_compact_rows(
+    compact_rows: &[CompactRow],
+    output: Option<OutputTarget>,
+    format: OutputFormat,
+    delimiter: u8,
+) -> io::Result<()> {
+    write_rows(compact_rows, output, format, delimiter)
+}
+
+fn write_rows<T: Serialize>(
+    rows: &[T],
+    output: Option<OutputTarget>,
+    format: OutputFormat,
+    delimiter: u8,
+) -> io::Result<()> {
+    let mut writer = open_output(output)?;
+    match format {
+        OutputFormat::Tsv => {
+            let mut csv_writer = WriterBuilder::new()
+                .delimiter(delimiter)
+                .from_writer(&mut writer);
+            for row in rows {
+                csv_writer.serialize(row)?;
             }
+            csv_writer.flush()?;
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, rows)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Ndjson => {
+            for row in rows {
+                serde_json::to_writer(&mut writer, row)?;
+                writeln!(writer)?;
+            }
+        }
+    }
+    writer.finish()
+}
+
+/// Sorts builds by build number and picks the Drone1 and, unless
+/// `config.drone1_only`, the Drone2 build of a commit to compare
+fn pick_builds<'a>(
+    key: &str,
+    drone1_builds: &'a mut [DroneBuildInfo],
+    drone2_builds: &'a mut [DroneBuildInfo],
+    config: &ReportConfig,
+) -> Result<(&'a DroneBuildInfo, Option<&'a DroneBuildInfo>), DropReason> {
+    // if there aren't builds to compare, continue
+    if drone1_builds.is_empty() || (drone2_builds.is_empty() && !config.drone1_only) {
+        debug!("No builds to compare for '{key}' in both drone1 and drone2");
+        return Err(DropReason::NoBuilds);
+    }
+
+    // order builds by build number
+    drone1_builds.sort_by_key(|build| build.build_info.number);
+    drone2_builds.sort_by_key(|build| build.build_info.number);
+
+    let drone2_build = if config.drone1_only {
+        Some(None)
+    } else {
+        config.pick.pick(drone2_builds).map(Some)
+    };
+    let (Some(drone1_build), Some(drone2_build)) = (config.pick.pick(drone1_builds), drone2_build)
+    else {
+        debug!("No successful builds to compare for '{key}' in both drone1 and drone2");
+        return Err(DropReason::NoPickedBuild);
+    };
+    Ok((drone1_build, drone2_build))
+}
+
+/// The order rows are reported in: by PR number, then git sha, then Drone1
+/// build number. Commits come out of a HashMap in arbitrary order; sorting
+/// keeps the reports of two runs over the same builds diffable.
+fn report_order<'a>(
+    pr_number: Option<&String>,
+    git_sha: &'a str,
+    drone1_build_number: u32,
+) -> (Option<u64>, &'a str, u32) {
+    let pr_number = pr_number.and_then(|number| number.parse::<u64>().ok());
+    (pr_number, git_sha, drone1_build_number)
+}
+
+/// Compares the build-level status and elapsed time of the picked Drone1 and
+/// Drone2 build of every commit, one row per commit in the order of
+/// `build_rows`. Only `pick`, `drone1_only` and `statuses` apply, the
+/// latter to the build statuses; the stage and step settings need the build
+/// details, which compact reports never fetch.
+pub fn compact_rows(
+    commit_build_map: CommitBuildMap,
+    config: &ReportConfig,
+    drop_counts: &mut DropCounts,
+) -> Vec<CompactRow> {
+    let mut rows = Vec::new();
+    for (key, (mut drone1_builds, mut drone2_builds)) in commit_build_map {
+        drop_counts.considered += 1;
+        let (drone1_build, drone2_build) =
+            match pick_builds(&key, &mut drone1_builds, &mut drone2_builds, config) {
+                Ok(builds) => builds,
+                Err(reason) => {
+                    drop_counts.record(reason, 1);
+                    continue;
+                }
+            };
+        let mut row = CompactRow {
+            repo: None,
+            pr_number: drone1_build.get_pr_number(),
+            pr_url: drone2_build.unwrap_or(drone1_build).get_pr_url(),
+            git_sha: drone1_build.build_info.git_metadata.git_sha.clone(),
+            drone1_build_number: drone1_build.build_info.number,
+            drone2_build_number: drone2_build.map(|build| build.build_info.number),
+            drone1_build_status: drone1_build.build_info.status,
+            drone2_build_status: drone2_build.map(|build| build.build_info.status),
+            drone1_build_elapsed_time: drone1_build.elapsed_time(),
+            drone2_build_elapsed_time: drone2_build.and_then(DroneBuildInfo::elapsed_time),
         };
+        if config.normalize_status {
+            row.normalize_statuses();
+        }
+        let statuses_match = config.statuses.is_empty()
+            || [Some(row.drone1_build_status), row.drone2_build_status]
+                .iter()
+                .flatten()
+                .all(|status| config.statuses.contains(status));
+        if statuses_match {
+            rows.push(row);
+        } else {
+            drop_counts.record(DropReason::StatusFiltered, 1);
+        }
+    }
+    rows.sort_by(|a, b| {
+        report_order(a.pr_number.as_ref(), &a.git_sha, a.drone1_build_number).cmp(&report_order(
+            b.pr_number.as_ref(),
+            &b.git_sha,
+            b.drone1_build_number,
+        ))
+    });
+    rows
+}
+
+/// Compares the Drone1 and Drone2 build `config.pick` picks for every commit,
+/// the first of each by default, producing a row per configured stage and
+/// step pair, ordered by PR number, then git sha, then Drone1 build number.
+///
+/// Counts every comparison left out of the report into `drop_counts`
+pub fn build_rows(
+    commit_build_map: CommitBuildMap,
+    config: &ReportConfig,
+    drop_counts: &mut DropCounts,
+) -> Vec<Row> {
+    let default_pair = StepPair {
+        stage: match config.mode {
+            BuildMode::PullRequest => config.pr_stage.clone(),
+            BuildMode::Develop => config.develop_stage.clone(),
+            BuildMode::Tag => config.tag_stage.clone(),
+        },
+        step: config.unit_test_step.clone(),
+    };
+    let pairs = if config.compare.is_empty() {
+        std::slice::from_ref(&default_pair)
+    } else {
+        &config.compare[..]
+    };
+
+    let mut rows = Vec::new();
+    for (key, (mut drone1_builds, mut drone2_builds)) in commit_build_map {
+        drop_counts.considered += pairs.len();
+        let (drone1_build, drone2_build) =
+            match pick_builds(&key, &mut drone1_builds, &mut drone2_builds, config) {
+                Ok(builds) => builds,
+                Err(reason) => {
+                    drop_counts.record(reason, pairs.len());
+                    continue;
+                }
+            };
 
-        let drone1_unit_test_status = drone1_unit_test_step.get_status();
-        let drone1_await_test_status = drone1_await_test_step.get_status();
-        let drone2_system_status = wallet_platform_system_status(drone2_build);
-
-        let drone1_unit_test_elapsed_time = drone1_unit_test_step.elapsed_time();
-        let drone2_total_elapsed_time = drone1_await_test_step.get_stopped_timestamp()
-            - drone2_build.build_info.timestamps.started;
-        let delta_await_complete_to_unit_test_start = drone1_await_test_step
-            .get_stopped_timestamp()
-            - drone1_unit_test_step.get_started_timestamp();
-        let await_within_three_minutes_of_unit_test_start =
-            delta_await_complete_to_unit_test_start < 60 * 5;
-        let delta_await_complete_to_unit_test_complete = drone1_await_test_step
-            .get_stopped_timestamp()
-            - drone1_unit_test_step.get_stopped_timestamp();
-        let await_faster_than_unit_test = drone1_await_test_step
-        .get_stopped_timestamp() < drone1_unit_test_step.get_stopped_timestamp();
-
-        let record = Row {
-            pr_number,
-            pr_url,
-            git_sha,
+        for pair in pairs {
+            let compared = (!config.compare.is_empty()).then(|| pair.to_string());
+            match build_row(drone1_build, drone2_build, pair, compared, config) {
+                Ok(mut row) => {
+                    if config.normalize_status {
+                        row.normalize_statuses();
+                    }
+                    if config.statuses_match(&row) {
+                        rows.push(row);
+                    } else {
+                        drop_counts.record(DropReason::StatusFiltered, 1);
+                    }
+                }
+                Err(reason) => drop_counts.record(reason, 1),
+            }
+        }
+    }
+    // the sort is stable, so the rows of a commit stay in the order of the
+    // configured pairs
+    rows.sort_by(|a, b| {
+        report_order(a.pr_number.as_ref(), &a.git_sha, a.drone1_build_number).cmp(&report_order(
+            b.pr_number.as_ref(),
+            &b.git_sha,
+            b.drone1_build_number,
+        ))
+    });
+    rows
+}
+
+/// Compares the step of `pair` against the Drone2 build, if any; fails when
+/// the stage or step is missing, skipped or unfinished in the Drone1 build
+fn build_row(
+    drone1_build: &DroneBuildInfo,
+    drone2_build: Option<&DroneBuildInfo>,
+    pair: &StepPair,
+    compared: Option<String>,
+    config: &ReportConfig,
+) -> Result<Row, DropReason> {
+    let stage_name = &pair.stage;
+    let unit_test_step_name = &pair.step;
+    let await_step_name = &config.await_step;
+    let git_sha = &drone1_build.build_info.git_metadata.git_sha;
+
+    let pr_number = drone1_build.get_pr_number();
+    let pr_url = drone2_build.unwrap_or(drone1_build).get_pr_url();
+    let drone1_build_number = drone1_build.build_info.number;
+    let drone2_build_number = drone2_build.map(|build| build.build_info.number);
+    let drone2_started = drone2_build.map(|build| build.build_info.timestamps.started);
+    let Some(drone1_stage) =
+        drone1_build.get_stage_of_type(stage_name, config.stage_type.as_deref())
+    else {
+        warn!("No stage '{stage_name}' in drone1 build '{drone1_build_number}'");
+        return Err(DropReason::MissingStage);
+    };
+    let Some(drone1_unit_test_step) = drone1_stage.get_step(unit_test_step_name) else {
+        warn!("No step '{unit_test_step_name}' in drone1 build '{drone1_build_number}'");
+        return Err(DropReason::MissingStep);
+    };
+    let skipped = drone1_unit_test_step.get_status() == DroneStatus::Skipped;
+    if skipped && !config.include_skipped {
+        debug!("Step '{unit_test_step_name}' skipped in drone1 build '{drone1_build_number}'");
+        return Err(DropReason::Skipped);
+    }
+    let Some(drone1_await_test_step) = drone1_stage.get_step(await_step_name) else {
+        warn!("No step '{await_step_name}' in drone1 build '{drone1_build_number}'");
+        return Err(DropReason::MissingStep);
+    };
+
+    let drone1_unit_test_status = drone1_unit_test_step.get_status();
+    let drone1_await_test_status = drone1_await_test_step.get_status();
+    let drone2_system_status = match drone2_build
+        .map(|build| {
+            wallet_platform_system_status(
+                build,
+                &config.system_stage_regex,
+                config.stage_type.as_deref(),
+            )
+        })
+        .transpose()
+    {
+        Ok(status) => status,
+        Err(error) => {
+            warn!("No system status for git sha '{git_sha}': {error}");
+            return Err(DropReason::NoSystemStatus);
+        }
+    };
+
+    // steps which haven't started or finished yet can't be compared; a
+    // skipped unit test step never runs, so its row is kept without timings
+    let timings = match (
+        drone1_unit_test_step.elapsed_time(),
+        drone1_unit_test_step.get_started_timestamp(),
+        drone1_unit_test_step.get_stopped_timestamp(),
+        drone1_await_test_step.get_stopped_timestamp(),
+    ) {
+        _ if skipped => None,
+        (
+            Some(unit_test_elapsed_time),
+            Some(unit_test_started),
+            Some(unit_test_stopped),
+            Some(await_stopped),
+        ) => Some((
+            unit_test_elapsed_time,
+            unit_test_started,
+            unit_test_stopped,
+            await_stopped,
+        )),
+        _ => {
+            warn!("Steps '{unit_test_step_name}' and '{await_step_name}' have not both finished in drone1 build '{drone1_build_number}'");
+            return Err(DropReason::Unfinished);
+        }
+    };
+
+    let drone1_unit_test_elapsed_time = timings.map(|(elapsed_time, ..)| elapsed_time);
+    let drone2_total_elapsed_time =
+        timings
+            .zip(drone2_started)
+            .and_then(|((.., await_stopped), drone2_started)| {
+                await_stopped.seconds_since(drone2_started)
+            });
+    let delta_await_complete_to_unit_test_start =
+        timings.and_then(|(_, unit_test_started, _, await_stopped)| {
+            await_stopped.seconds_since(unit_test_started)
+        });
+    let await_within_three_minutes_of_unit_test_start = delta_await_complete_to_unit_test_start
+        .map(|delta| {
+            let delta = if config.await_abs { delta.abs() } else { delta };
+            delta < config.await_threshold_secs
+        });
+    let delta_await_complete_to_unit_test_complete =
+        timings.and_then(|(_, _, unit_test_stopped, await_stopped)| {
+            await_stopped.seconds_since(unit_test_stopped)
+        });
+    let await_faster_than_unit_test =
+        timings.map(|(_, _, unit_test_stopped, await_stopped)| await_stopped < unit_test_stopped);
+    let delta_drone2_start_to_await_start = timings
+        .and(drone1_await_test_step.get_started_timestamp())
+        .zip(drone2_started)
+        .and_then(|(await_started, drone2_started)| await_started.seconds_since(drone2_started));
+    let drone2_started_before_drone1_created = drone2_started.and_then(|drone2_started| {
+        drone1_build
+            .build_info
+            .timestamps
+            .created
+            .seconds_since(drone2_started)
+    });
+    let clock_skew_suspected = drone2_total_elapsed_time.map(|elapsed_time| {
+        elapsed_time < -config.skew_tolerance_secs
+            || drone2_started_before_drone1_created
+                .is_some_and(|seconds| seconds > config.skew_tolerance_secs)
+    });
+    let statuses_agree = drone2_system_status.map(|drone2_system_status| {
+        (drone1_unit_test_status == DroneStatus::Success)
+            == (drone2_system_status == DroneStatus::Success)
+    });
+
+    Ok(Row {
+        repo: None,
+        pr_number,
+        pr_url,
+        git_sha: git_sha.clone(),
+        drone1_build_number,
+        drone2_build_number,
+        drone1_unit_test_status,
+        drone1_await_test_status,
+        drone2_system_status,
+        drone1_unit_test_elapsed_time,
+        drone2_total_elapsed_time,
+        await_within_three_minutes_of_unit_test_start,
+        delta_await_complete_to_unit_test_start,
+        await_faster_than_unit_test,
+        delta_await_complete_to_unit_test_complete,
+        delta_drone2_start_to_await_start,
+        statuses_agree,
+        clock_skew_suspected,
+        compared,
+        baseline_delta_drone1_unit_test_elapsed_time: None,
+        baseline_delta_drone2_total_elapsed_time: None,
+        drone1_await_error_tail: None,
+        drone1_trigger: drone1_build.build_info.trigger.clone(),
+        drone2_sender: drone2_build.map(|build| build.build_info.sender.clone()),
+        drone1_unit_test_step: StepLocation::new(
             drone1_build_number,
-            drone2_build_number,
-            drone1_unit_test_status,
-            drone1_await_test_status,
-            drone2_system_status,
-            drone1_unit_test_elapsed_time,
-            drone2_total_elapsed_time,
-            await_within_three_minutes_of_unit_test_start,
-            delta_await_complete_to_unit_test_start,
-            await_faster_than_unit_test,
-            delta_await_complete_to_unit_test_complete,
+            drone1_stage,
+            drone1_unit_test_step,
+        ),
+        drone1_await_test_step: StepLocation::new(
+            drone1_build_number,
+            drone1_stage,
+            drone1_await_test_step,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// The command line's defaults
+    fn report_config() -> ReportConfig {
+        ReportConfig {
+            mode: BuildMode::PullRequest,
+            await_threshold_secs: 300,
+            await_abs: false,
+            skew_tolerance_secs: 60,
+            pr_stage: "build-pull-request".to_string(),
+            develop_stage: "build-develop".to_string(),
+            tag_stage: "build-tag".to_string(),
+            unit_test_step: "run-wallet-platform-unit-tests".to_string(),
+            await_step: "await-wallet-platform-test-status".to_string(),
+            compare: Vec::new(),
+            include_skipped: false,
+            normalize_status: false,
+            pick: PickStrategy::First,
+            system_stage_regex: Regex::new("^wallet-platform-.*").unwrap(),
+            stage_type: None,
+            drone1_only: false,
+            statuses: Vec::new(),
+        }
+    }
+
+    fn build_json(number: u32, git_sha: &str, pr_number: u32, stages: Value) -> Value {
+        json!({
+            "id": number, "repo_id": 1, "trigger": "@hook", "number": number,
+            "status": "success", "event": "pull_request", "action": "sync",
+            "link": format!("https://github.com/o/r/pull/{pr_number}.diff"),
+            "timestamp": 0, "message": "", "before": "", "after": git_sha,
+            "ref": format!("refs/pull/{pr_number}/head"), "source_repo": "o/r",
+            "source": "feature", "target": "develop", "author_login": "octocat",
+            "author_name": "", "author_email": "", "author_avatar": "https://x/",
+            "sender": "octocat", "started": 1000, "finished": 2000, "created": 1000,
+            "updated": 2000, "version": 1, "stages": stages,
+        })
+    }
+
+    fn stage_json(number: u32, name: &str, status: DroneStatus, steps: Value) -> Value {
+        json!({
+            "id": number, "repo_id": 1, "build_id": 1, "number": number, "name": name,
+            "status": status, "errignore": false, "exit_code": 0, "machine": null,
+            "os": "linux", "arch": "amd64", "started": 1000, "stopped": 2000,
+            "created": 1000, "updated": 2000, "version": 1, "on_success": true,
+            "on_failure": false, "steps": steps,
+        })
+    }
+
+    fn step_json(number: u32, name: &str, started: i64, stopped: i64) -> Value {
+        json!({
+            "id": number, "step_id": number, "number": number, "name": name,
+            "status": "success", "errignore": false, "exit_code": 0,
+            "started": started, "stopped": stopped, "version": 1,
+        })
+    }
+
+    /// A Drone1 build whose unit test and await steps run over the
+    /// `(started, stopped)` seconds given
+    fn drone1_build(
+        number: u32,
+        git_sha: &str,
+        pr_number: u32,
+        unit_test: (i64, i64),
+        await_test: (i64, i64),
+    ) -> DroneBuildInfo {
+        let steps = json!([
+            step_json(
+                1,
+                "run-wallet-platform-unit-tests",
+                unit_test.0,
+                unit_test.1
+            ),
+            step_json(
+                2,
+                "await-wallet-platform-test-status",
+                await_test.0,
+                await_test.1
+            ),
+        ]);
+        let stages = json!([stage_json(
+            1,
+            "build-pull-request",
+            DroneStatus::Success,
+            steps
+        )]);
+        serde_json::from_value(build_json(number, git_sha, pr_number, stages)).unwrap()
+    }
+
+    /// A Drone2 build of `git_sha` whose system stage is in `system_status`
+    fn drone2_build(
+        number: u32,
+        git_sha: &str,
+        pr_number: u32,
+        system_status: DroneStatus,
+    ) -> DroneBuildInfo {
+        let mut system_stage = stage_json(1, "wallet-platform-system", system_status, json!([]));
+        system_stage["kind"] = "pipeline".into();
+        system_stage["type"] = "kubernetes".into();
+        system_stage["depends_on"] = Value::Null;
+        let build = build_json(number, git_sha, pr_number, json!([system_stage]));
+        serde_json::from_value(build).unwrap()
+    }
+
+    fn commit(
+        drone1_builds: Vec<DroneBuildInfo>,
+        drone2_builds: Vec<DroneBuildInfo>,
+    ) -> (String, (Vec<DroneBuildInfo>, Vec<DroneBuildInfo>)) {
+        let git_sha = drone1_builds[0].build_info.git_metadata.git_sha.clone();
+        (git_sha, (drone1_builds, drone2_builds))
+    }
+
+    #[test]
+    fn rows_without_a_system_status_are_dropped() {
+        let commit_build_map = CommitBuildMap::from([
+            commit(
+                vec![drone1_build(1, "aaa", 1, (1000, 1100), (1000, 1200))],
+                vec![drone2_build(11, "aaa", 1, DroneStatus::Error)],
+            ),
+            commit(
+                vec![drone1_build(2, "bbb", 2, (1000, 1100), (1000, 1200))],
+                vec![drone2_build(12, "bbb", 2, DroneStatus::Running)],
+            ),
+        ]);
+        let mut drop_counts = DropCounts::default();
+
+        let rows = build_rows(commit_build_map, &report_config(), &mut drop_counts);
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].git_sha, "aaa");
+        assert_eq!(rows[0].drone2_system_status, Some(DroneStatus::Failure));
+        assert_eq!(rows[0].statuses_agree, Some(false));
+        assert_eq!(drop_counts.considered, 2);
+        assert_eq!(drop_counts.no_system_status, 1);
+    }
+
+    #[test]
+    fn rows_come_out_in_the_same_order_every_run() {
+        // every map is seeded differently, so each run walks the commits in
+        // another order
+        let commit_build_map = || -> CommitBuildMap {
+            (1..=30)
+                .map(|number| {
+                    let git_sha = format!("{:03}", 31 - number);
+                    // pull request numbers sort numerically, not as text
+                    let pr_number = [5, 40, 300][number as usize % 3];
+                    commit(
+                        vec![drone1_build(
+                            number,
+                            &git_sha,
+                            pr_number,
+                            (1000, 1100),
+                            (1000, 1200),
+                        )],
+                        vec![drone2_build(
+                            100 + number,
+                            &git_sha,
+                            pr_number,
+                            DroneStatus::Success,
+                        )],
+                    )
+                })
+                .collect()
+        };
+        let row_order = || -> Vec<(Option<String>, String, u32)> {
+            build_rows(
+                commit_build_map(),
+                &report_config(),
+                &mut DropCounts::default(),
+            )
+            .into_iter()
+            .map(|row| (row.pr_number, row.git_sha, row.drone1_build_number))
+            .collect()
+        };
+
+        let first_run = row_order();
+        assert_eq!(first_run.len(), 30);
+        for _ in 0..5 {
+            assert_eq!(row_order(), first_run);
+        }
+        let pr_numbers: Vec<u64> = first_run
+            .iter()
+            .map(|(pr_number, ..)| pr_number.as_ref().unwrap().parse().unwrap())
+            .collect();
+        assert!(pr_numbers.is_sorted());
+        assert_eq!(pr_numbers[0], 5);
+        assert!(first_run
+            .chunk_by(|a, b| a.0 == b.0)
+            .all(|rows| rows.is_sorted_by_key(|(_, git_sha, _)| git_sha.clone())));
+    }
+
+    #[test]
+    fn await_deltas_are_compared_signed_unless_await_abs() {
+        // the await step stops 600s before, at, and 600s after the unit test
+        // step starts at 2000
+        let commit_build_map = || {
+            CommitBuildMap::from_iter([-600, 0, 600].into_iter().enumerate().map(
+                |(index, delta)| {
+                    let number = index as u32 + 1;
+                    let git_sha = format!("sha{number}");
+                    commit(
+                        vec![drone1_build(
+                            number,
+                            &git_sha,
+                            number,
+                            (2000, 2100),
+                            (1000, 2000 + delta),
+                        )],
+                        vec![drone2_build(
+                            10 + number,
+                            &git_sha,
+                            number,
+                            DroneStatus::Success,
+                        )],
+                    )
+                },
+            ))
+        };
+        let within_threshold = |config: &ReportConfig| -> Vec<(Option<i64>, Option<bool>)> {
+            build_rows(commit_build_map(), config, &mut DropCounts::default())
+                .into_iter()
+                .map(|row| {
+                    (
+                        row.delta_await_complete_to_unit_test_start,
+                        row.await_within_three_minutes_of_unit_test_start,
+                    )
+                })
+                .collect()
+        };
+
+        let mut config = report_config();
+        assert_eq!(
+            within_threshold(&config),
+            [
+                (Some(-600), Some(true)),
+                (Some(0), Some(true)),
+                (Some(600), Some(false)),
+            ]
+        );
+        config.await_abs = true;
+        assert_eq!(
+            within_threshold(&config),
+            [
+                (Some(-600), Some(false)),
+                (Some(0), Some(true)),
+                (Some(600), Some(false)),
+            ]
+        );
+    }
+
+    /// Takes every row, then fails to finish, like an S3 upload without
+    /// credentials
+    struct FailingSink;
+
+    impl Write for FailingSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl OutputSink for FailingSink {
+        fn finish(&mut self) -> io::Result<()> {
+            Err(io::Error::other("upload denied"))
+        }
+    }
+
+    #[test]
+    fn reports_that_cant_be_finished_are_errors() {
+        for format in [OutputFormat::Tsv, OutputFormat::Json, OutputFormat::Ndjson] {
+            let report_writer = ReportWriter::from_writer(Box::new(FailingSink), format, b'\t');
+            let error = report_writer.finish().unwrap_err();
+            assert_eq!(error.to_string(), "upload denied");
+        }
+    }
+
+    #[test]
+    fn unwritable_output_paths_are_errors() {
+        let path = std::env::temp_dir().join("cuddly-robot-missing-dir/report.tsv");
+        let error = ReportWriter::new(
+            Some(OutputTarget::File(path.clone())),
+            OutputFormat::Tsv,
+            b'\t',
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains(&path.display().to_string()));
+
+        let error = write_compact_rows(
+            &[],
+            Some(OutputTarget::File(path)),
+            OutputFormat::Json,
+            b'\t',
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn unappendable_output_paths_are_errors() {
+        let missing_dir = std::env::temp_dir().join("cuddly-robot-missing-dir/report.tsv");
+        for (path, kind) in [
+            (std::env::temp_dir(), io::ErrorKind::IsADirectory),
+            (missing_dir, io::ErrorKind::NotFound),
+        ] {
+            let error = ReportWriter::append(path.clone(), OutputFormat::Tsv, b'\t')
+                .err()
+                .unwrap();
+            assert_eq!(error.kind(), kind);
+            assert!(error.to_string().contains(&path.display().to_string()));
+        }
+    }
+
+    #[test]
+    fn clock_skew_is_suspected_either_way() {
+        // the Drone1 build is created at 1000 and its await step finishes at
+        // 1200
+        let clock_skew_suspected = |drone2_started| {
+            let mut drone2_build = drone2_build(11, "aaa", 1, DroneStatus::Success);
+            drone2_build.build_info.timestamps.started = DroneTime(drone2_started);
+            let commit_build_map = CommitBuildMap::from([commit(
+                vec![drone1_build(1, "aaa", 1, (1000, 1100), (1000, 1200))],
+                vec![drone2_build],
+            )]);
+            let rows = build_rows(
+                commit_build_map,
+                &report_config(),
+                &mut DropCounts::default(),
+            );
+            (
+                rows[0].drone2_total_elapsed_time,
+                rows[0].clock_skew_suspected,
+            )
+        };
+        assert_eq!(clock_skew_suspected(1000), (Some(200), Some(false)));
+        // started after the await step waiting on it finished
+        assert_eq!(clock_skew_suspected(1250), (Some(-50), Some(false)));
+        assert_eq!(clock_skew_suspected(1300), (Some(-100), Some(true)));
+        // started before the push created the Drone1 build
+        assert_eq!(clock_skew_suspected(940), (Some(260), Some(false)));
+        assert_eq!(clock_skew_suspected(900), (Some(300), Some(true)));
+        assert_eq!(clock_skew_suspected(1), (Some(1199), Some(true)));
+    }
+
+    #[test]
+    fn builds_are_picked_by_the_pick_strategy() {
+        // builds 2 and 3 rerun build 1, and the last rerun failed
+        let mut failed = drone1_build(3, "aaa", 1, (1000, 1100), (1000, 1200));
+        failed.build_info.status = DroneStatus::Failure;
+        let commit_build_map = || {
+            CommitBuildMap::from([commit(
+                vec![
+                    failed.clone(),
+                    drone1_build(1, "aaa", 1, (1000, 1100), (1000, 1200)),
+                    drone1_build(2, "aaa", 1, (1000, 1100), (1000, 1200)),
+                ],
+                vec![drone2_build(11, "aaa", 1, DroneStatus::Success)],
+            )])
+        };
+        let picked = |pick| {
+            let config = ReportConfig {
+                pick,
+                ..report_config()
+            };
+            let rows = build_rows(commit_build_map(), &config, &mut DropCounts::default());
+            rows.iter()
+                .map(|row| (row.drone1_build_number, row.drone2_build_number))
+                .collect::<Vec<_>>()
         };
-        csv_writer.serialize(record).unwrap();
+        assert_eq!(picked(PickStrategy::First), [(1, Some(11))]);
+        assert_eq!(picked(PickStrategy::Last), [(3, Some(11))]);
+        assert_eq!(picked(PickStrategy::LatestSuccess), [(2, Some(11))]);
     }
 }
@@ -1,127 +1,1287 @@
-use reqwest::blocking::{Client, ClientBuilder};
-use reqwest::header::{HeaderMap, AUTHORIZATION};
-use reqwest::Url;
+use crate::graph::{GraphStage, StageGraph};
+use bytes::Bytes;
+use chrono::DateTime;
+use clap::ValueEnum;
+use derive_more::{Display, Error};
+use log::{debug, warn};
+use regex::Regex;
+use reqwest::blocking::{Client, ClientBuilder, RequestBuilder, Response};
+use reqwest::header::{HeaderMap, InvalidHeaderValue, AUTHORIZATION, RETRY_AFTER, USER_AGENT};
+use reqwest::{Certificate, Proxy};
+use reqwest::{StatusCode, Url};
+use serde::de::DeserializeOwned;
 use serde::*;
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "async")]
+mod async_client;
+
+#[cfg(feature = "async")]
+pub use async_client::{AsyncDroneBuildsPaginator, AsyncDroneClient};
+
+/// Drone1 and Drone2 builds, in that order, keyed by the git sha they built
+/// or, depending on the `Correlation`, their pull request number
+pub type CommitBuildMap = HashMap<String, (Vec<DroneBuildInfo>, Vec<DroneBuildInfo>)>;
+
+/// What the builds of the two Drone instances are matched up by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Correlation {
+    /// The git sha built, so both builds compared are of the same commit;
+    /// instances building different shas of a pull request, e.g. the merge
+    /// commit and the head, are never matched
+    Sha,
+    /// The pull request number, matching builds of a pull request whatever
+    /// sha they built. A pull request pushed several times is compared once,
+    /// with its build picked across all of its pushes, so the two builds
+    /// compared may be of different commits. Builds without a pull request
+    /// number, e.g. pushes and tags, are still matched by sha
+    Pr,
+}
+
+impl Correlation {
+    /// The `CommitBuildMap` key of `build`
+    pub fn key(self, build: &DroneBuildInfo) -> String {
+        match (self, build.get_pr_number()) {
+            (Correlation::Pr, Some(pr_number)) => pr_number,
+            _ => build.build_info.git_metadata.git_sha.clone(),
+        }
+    }
+}
+
+/// Identifies which Drone API request failed, so errors can point at the
+/// page or build that was being fetched
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum DroneRequest {
+    #[display(fmt = "build list page {}", page)]
+    BuildList { page: usize },
+    #[display(fmt = "build list before build id {}", before)]
+    BuildListBefore { before: u32 },
+    #[display(fmt = "build {}", build_number)]
+    BuildInfo { build_number: u32 },
+    #[display(
+        fmt = "logs of build {} stage {} step {}",
+        build_number,
+        stage_number,
+        step_number
+    )]
+    StepLogs {
+        build_number: u32,
+        stage_number: u32,
+        step_number: u32,
+    },
+    #[display(fmt = "restart of build {}", build_number)]
+    RestartBuild { build_number: u32 },
+    #[display(fmt = "repository list")]
+    UserRepos,
+    #[display(fmt = "repository")]
+    Repo,
+}
+
+/// The two Drone servers whose builds are compared
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DroneInstance {
+    #[display(fmt = "drone1")]
+    Drone1,
+    #[display(fmt = "drone2")]
+    Drone2,
+}
+
+#[derive(Debug, Display, Error)]
+pub enum DroneError {
+    #[display(fmt = "invalid Drone token: {}", _0)]
+    InvalidToken(InvalidHeaderValue),
+    #[display(fmt = "invalid User-Agent: {}", _0)]
+    InvalidUserAgent(InvalidHeaderValue),
+    #[display(fmt = "failed to build HTTP client: {}", _0)]
+    Client(reqwest::Error),
+    #[display(fmt = "invalid proxy: {}", _0)]
+    InvalidProxy(reqwest::Error),
+    #[display(
+        fmt = "failed to read CA certificate '{}': {}",
+        "path.display()",
+        source
+    )]
+    ReadCaCert { path: PathBuf, source: io::Error },
+    #[display(fmt = "invalid CA certificate '{}': {}", "path.display()", source)]
+    InvalidCaCert {
+        path: PathBuf,
+        source: reqwest::Error,
+    },
+    #[display(fmt = "failed to request {} from '{}': {}", request, url, source)]
+    Connection {
+        request: DroneRequest,
+        url: Url,
+        source: reqwest::Error,
+    },
+    #[display(fmt = "timed out requesting {} from '{}'", request, url)]
+    Timeout {
+        request: DroneRequest,
+        url: Url,
+        source: reqwest::Error,
+    },
+    #[display(
+        fmt = "failed to request {} from '{}': HTTP {}{}",
+        request,
+        url,
+        status,
+        "message.as_ref().map(|message| format!(\": {message}\")).unwrap_or_default()"
+    )]
+    Status {
+        request: DroneRequest,
+        /// Boxed to keep `DroneError` small
+        url: Box<Url>,
+        status: StatusCode,
+        /// Drone's explanation, e.g. `repository not found`; the raw body
+        /// when it isn't one of Drone's JSON errors
+        message: Option<String>,
+    },
+    #[display(fmt = "rate limited requesting {} from '{}'", request, url)]
+    RateLimited {
+        request: DroneRequest,
+        url: Url,
+        /// How long Drone asked to wait, from its `Retry-After` header
+        #[error(not(source))]
+        retry_after: Option<Duration>,
+    },
+    #[display(
+        fmt = "not allowed to request {} from '{}': the token needs write access",
+        request,
+        url
+    )]
+    Forbidden { request: DroneRequest, url: Url },
+    #[display(fmt = "failed to deserialize {}: {}", request, source)]
+    Deserialize {
+        request: DroneRequest,
+        source: serde_json::Error,
+    },
+    #[display(fmt = "{} does not have the shape of a {} build", request, instance)]
+    UnexpectedShape {
+        request: DroneRequest,
+        #[error(not(source))]
+        instance: DroneInstance,
+    },
+}
+
+impl DroneError {
+    /// Connection failures, 429 and 5xx responses and bodies that end
+    /// mid-JSON, e.g. cut short by a proxy, are worth retrying; anything else
+    /// (other 4xx, payloads of the wrong shape, misconfiguration) will fail
+    /// the same way again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DroneError::Connection { .. }
+            | DroneError::Timeout { .. }
+            | DroneError::RateLimited { .. } => true,
+            DroneError::Status { status, .. } => status.is_server_error(),
+            DroneError::Deserialize { source, .. } => source.is_eof(),
+            _ => false,
+        }
+    }
+
+    fn transport(request: DroneRequest, url: Url, source: reqwest::Error) -> Self {
+        if source.is_timeout() {
+            DroneError::Timeout {
+                request,
+                url,
+                source,
+            }
+        } else {
+            DroneError::Connection {
+                request,
+                url,
+                source,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "invalid repository slug '{}', expected 'owner/name'", _0)]
+pub struct InvalidRepoSlug(#[error(not(source))] String);
+
+/// A Drone repository in `owner/name` form, e.g. `BitGo/bitgo-microservices`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RepoSlug {
+    pub owner: String,
+    pub name: String,
+}
+
+impl FromStr for RepoSlug {
+    type Err = InvalidRepoSlug;
+
+    fn from_str(slug: &str) -> Result<Self, Self::Err> {
+        let valid_part = |part: &str| {
+            !part.is_empty() && !part.contains(|c: char| c == '/' || c.is_whitespace())
+        };
+        match slug.split_once('/') {
+            Some((owner, name)) if valid_part(owner) && valid_part(name) => Ok(RepoSlug {
+                owner: owner.to_string(),
+                name: name.to_string(),
+            }),
+            _ => Err(InvalidRepoSlug(slug.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for RepoSlug {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.owner, self.name)
+    }
+}
+
+/// How many times, and how patiently, idempotent requests are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    /// Longest wait before a retry, however long the backoff or Drone's
+    /// `Retry-After` asks for, so a server asking for a day doesn't hold up
+    /// the run for one
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff (base, 2 * base, 4 * base, ...) plus up to half
+    /// of the base delay in random jitter
+    fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.base_delay.saturating_mul(2u32.saturating_pow(retry));
+        let jitter = self.base_delay.mul_f64(rand::random::<f64>() / 2.0);
+        backoff + jitter
+    }
+
+    /// How long to wait before retrying after `error`, honouring the wait
+    /// Drone asked for when it rate limited the request, up to `max_delay`
+    fn delay_after(&self, error: &DroneError, retry: u32) -> Duration {
+        let delay = match error {
+            DroneError::RateLimited {
+                retry_after: Some(retry_after),
+                ..
+            } => *retry_after,
+            _ => self.delay(retry),
+        };
+        delay.min(self.max_delay)
+    }
+}
+
+/// Token bucket shared by every client it is given to, so that together they
+/// send at most `requests_per_second` requests on average, with bursts of up
+/// to one second's worth
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    requests_per_second: f64,
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    /// Negative while requests are waiting for tokens yet to be refilled
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// `requests_per_second` must be positive
+    pub fn new(requests_per_second: f64) -> Self {
+        RateLimiter {
+            requests_per_second,
+            bucket: Arc::new(Mutex::new(TokenBucket {
+                tokens: requests_per_second.max(1.0),
+                refilled_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// Blocks until a request may be sent
+    fn acquire(&self) {
+        if let Some(wait) = self.reserve() {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Takes a token, returning how long to wait before the request may be
+    /// sent
+    fn reserve(&self) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refilled =
+            now.duration_since(bucket.refilled_at).as_secs_f64() * self.requests_per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(self.requests_per_second.max(1.0));
+        bucket.refilled_at = now;
+        // the token is taken right away, so that waiting requests are let
+        // through in the order they arrived
+        bucket.tokens -= 1.0;
+        (bucket.tokens < 0.0)
+            .then(|| Duration::from_secs_f64(-bucket.tokens / self.requests_per_second))
+    }
+}
+
+/// Semaphore capping how many requests of the clients it is given to are in
+/// flight at once, from before a request is sent until its body has been
+/// read. `RateLimiter` only spaces requests out over time, so a slow Drone
+/// can still have every worker's request pile up on it; this bounds the pile.
+/// A request holds its place while it waits on the `RateLimiter`, so the two
+/// together let at most `max_in_flight` requests wait or be sent at a time.
+#[derive(Debug, Clone)]
+pub struct RequestLimit {
+    max_in_flight: usize,
+    in_flight: Arc<(Mutex<usize>, Condvar)>,
+}
+
+/// A place taken from a `RequestLimit`, given back when dropped
+struct RequestPermit<'limit> {
+    request_limit: &'limit RequestLimit,
+}
+
+impl RequestLimit {
+    /// `max_in_flight` must be positive
+    pub fn new(max_in_flight: usize) -> Self {
+        RequestLimit {
+            max_in_flight,
+            in_flight: Arc::default(),
+        }
+    }
+
+    /// Blocks until fewer than `max_in_flight` requests are in flight
+    fn acquire(&self) -> RequestPermit<'_> {
+        let (in_flight, released) = &*self.in_flight;
+        let mut in_flight = released
+            .wait_while(in_flight.lock().unwrap(), |in_flight| {
+                *in_flight >= self.max_in_flight
+            })
+            .unwrap();
+        *in_flight += 1;
+        RequestPermit {
+            request_limit: self,
+        }
+    }
+}
+
+impl Drop for RequestPermit<'_> {
+    fn drop(&mut self) {
+        let (in_flight, released) = &*self.request_limit.in_flight;
+        *in_flight.lock().unwrap() -= 1;
+        released.notify_one();
+    }
+}
+
+pub const DEFAULT_USER_AGENT: &str = concat!("cuddly-robot/", env!("CARGO_PKG_VERSION"));
+
+/// The `message` of a Drone error body such as `{"message":"Not Found"}`, or
+/// the body itself, shortened, when it's something else; `None` when empty
+fn error_message(body: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct DroneErrorBody {
+        message: String,
+    }
+
+    const MAX_LENGTH: usize = 200;
+    let message = match serde_json::from_str::<DroneErrorBody>(body) {
+        Ok(error_body) => error_body.message,
+        Err(_) => body.trim().to_string(),
+    };
+    if message.is_empty() {
+        return None;
+    }
+    Some(match message.char_indices().nth(MAX_LENGTH) {
+        Some((end, _)) => format!("{}...", &message[..end]),
+        None => message,
+    })
+}
+
+/// Total number of items of a paged list, sent by some Drone deployments
+const TOTAL_COUNT: &str = "x-total-count";
+
+/// The wait asked for by the `Retry-After` header of a 429 response
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(RETRY_AFTER)
+        .and_then(|retry_after| retry_after.to_str().ok())
+        .and_then(parse_retry_after)
+}
+
+/// Parses a `Retry-After` header, which is either a number of seconds or an
+/// HTTP date; a date in the past means no wait at all
+fn parse_retry_after(retry_after: &str) -> Option<Duration> {
+    if let Ok(seconds) = retry_after.trim().parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let retry_at = SystemTime::from(DateTime::parse_from_rfc2822(retry_after).ok()?);
+    Some(
+        retry_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default(),
+    )
+}
+
+/// Largest `per_page` Drone accepts when listing builds
+pub const MAX_PAGE_SIZE: usize = 100;
 
 #[derive(Debug, Clone)]
 pub struct DroneClient {
     client: Client,
     url: Url,
+    repo: RepoSlug,
+    retry_policy: RetryPolicy,
+    rate_limiter: Option<RateLimiter>,
+    request_limit: Option<RequestLimit>,
+    ramp_up: Duration,
+    cache_dir: Option<PathBuf>,
+    instance: DroneInstance,
+    /// Builds `get_build_info_batch` has left out for their shape, shared
+    /// by the clones of the client
+    skipped_builds: Arc<AtomicUsize>,
 }
 
-impl DroneClient {
-    pub fn new_with_credentials(url: &'static str, mut credentials: String) -> Self {
-        credentials.insert_str(0, "Bearer ");
+#[derive(Debug, Clone)]
+pub struct DroneClientBuilder {
+    url: Url,
+    repo: RepoSlug,
+    credentials: String,
+    retry_policy: RetryPolicy,
+    rate_limiter: Option<RateLimiter>,
+    request_limit: Option<RequestLimit>,
+    ramp_up: Duration,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout: Option<Duration>,
+    cache_dir: Option<PathBuf>,
+    user_agent: String,
+    proxy: Option<Url>,
+    no_proxy: bool,
+    ca_cert: Option<PathBuf>,
+    insecure_skip_tls_verify: bool,
+    no_compression: bool,
+    instance: DroneInstance,
+}
+
+impl DroneClientBuilder {
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Holds every request, retries included, until `rate_limiter` lets it
+    /// through
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Holds every request, retries included, until `request_limit` has room
+    /// for it, which `get_build_info_batch` relies on over its `concurrency`.
+    /// Async clients, which only send requests as they're awaited, go
+    /// without it
+    pub fn request_limit(mut self, request_limit: RequestLimit) -> Self {
+        self.request_limit = Some(request_limit);
+        self
+    }
+
+    /// Delays the first request of each `get_build_info_batch` worker by a
+    /// random time up to `ramp_up`, so a batch doesn't start with every
+    /// worker's request arriving at once
+    pub fn ramp_up(mut self, ramp_up: Duration) -> Self {
+        self.ramp_up = ramp_up;
+        self
+    }
+
+    /// Total time allowed for a single request, from connecting until the
+    /// response body has been read
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Most idle connections kept open to the Drone instance for reuse; one
+    /// per concurrent request keeps every request from reconnecting
+    pub fn pool_max_idle_per_host(mut self, pool_max_idle_per_host: usize) -> Self {
+        self.pool_max_idle_per_host = Some(pool_max_idle_per_host);
+        self
+    }
+
+    /// How long an idle connection is kept open for reuse
+    pub fn pool_idle_timeout(mut self, pool_idle_timeout: Duration) -> Self {
+        self.pool_idle_timeout = Some(pool_idle_timeout);
+        self
+    }
+
+    /// Identifies the tool in the Drone servers' access logs; defaults to
+    /// `DEFAULT_USER_AGENT`
+    pub fn user_agent(mut self, user_agent: String) -> Self {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Sends every request through `proxy` instead of the proxy configured
+    /// by the `HTTPS_PROXY`/`HTTP_PROXY` environment variables
+    pub fn proxy(mut self, proxy: Url) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Connects directly, ignoring any proxy
+    pub fn no_proxy(mut self) -> Self {
+        self.no_proxy = true;
+        self
+    }
+
+    /// Trusts the PEM certificate at `ca_cert` as a root in addition to the
+    /// system trust store, e.g. for a Drone instance behind a private CA
+    pub fn ca_cert(mut self, ca_cert: PathBuf) -> Self {
+        self.ca_cert = Some(ca_cert);
+        self
+    }
+
+    /// Accepts any TLS certificate, including expired and self-signed ones;
+    /// only meant for development instances
+    pub fn insecure_skip_tls_verify(mut self) -> Self {
+        self.insecure_skip_tls_verify = true;
+        self
+    }
+
+    /// Asks for uncompressed responses; by default gzip and deflate are
+    /// accepted, which shrinks large build lists considerably
+    pub fn no_compression(mut self) -> Self {
+        self.no_compression = true;
+        self
+    }
+
+    /// Directory in which the raw details of finished builds are cached
+    pub fn cache_dir(mut self, cache_dir: PathBuf) -> Self {
+        self.cache_dir = Some(cache_dir);
+        self
+    }
+
+    /// The token and User-Agent headers sent with every request
+    fn default_headers(&self) -> Result<HeaderMap, DroneError> {
         let mut headers = HeaderMap::new();
-        headers.insert(AUTHORIZATION, credentials.parse().unwrap());
-        let client = ClientBuilder::new()
-            .default_headers(headers)
-            .build()
-            .unwrap();
-        DroneClient {
+        headers.insert(
+            AUTHORIZATION,
+            format!("Bearer {}", self.credentials)
+                .parse()
+                .map_err(DroneError::InvalidToken)?,
+        );
+        headers.insert(
+            USER_AGENT,
+            self.user_agent
+                .parse()
+                .map_err(DroneError::InvalidUserAgent)?,
+        );
+        Ok(headers)
+    }
+
+    fn ca_certificate(&self) -> Result<Option<Certificate>, DroneError> {
+        let Some(path) = &self.ca_cert else {
+            return Ok(None);
+        };
+        let pem = fs::read(path).map_err(|source| DroneError::ReadCaCert {
+            path: path.clone(),
+            source,
+        })?;
+        Certificate::from_pem(&pem)
+            .map(Some)
+            .map_err(|source| DroneError::InvalidCaCert {
+                path: path.clone(),
+                source,
+            })
+    }
+
+    pub fn build(self) -> Result<DroneClient, DroneError> {
+        let mut client_builder = ClientBuilder::new().default_headers(self.default_headers()?);
+        if let Some(timeout) = self.timeout {
+            client_builder = client_builder.timeout(timeout);
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            client_builder = client_builder.connect_timeout(connect_timeout);
+        }
+        if let Some(pool_max_idle_per_host) = self.pool_max_idle_per_host {
+            client_builder = client_builder.pool_max_idle_per_host(pool_max_idle_per_host);
+        }
+        if let Some(pool_idle_timeout) = self.pool_idle_timeout {
+            client_builder = client_builder.pool_idle_timeout(pool_idle_timeout);
+        }
+        if let Some(certificate) = self.ca_certificate()? {
+            client_builder = client_builder.add_root_certificate(certificate);
+        }
+        if self.insecure_skip_tls_verify {
+            client_builder = client_builder.danger_accept_invalid_certs(true);
+        }
+        if self.no_compression {
+            client_builder = client_builder.no_gzip().no_deflate();
+        }
+        if self.no_proxy {
+            client_builder = client_builder.no_proxy();
+        } else if let Some(proxy) = self.proxy {
+            client_builder =
+                client_builder.proxy(Proxy::all(proxy).map_err(DroneError::InvalidProxy)?);
+        }
+        let client = client_builder.build().map_err(DroneError::Client)?;
+        Ok(DroneClient {
             client,
-            url: Url::parse(url).unwrap(),
+            url: self.url,
+            repo: self.repo,
+            retry_policy: self.retry_policy,
+            rate_limiter: self.rate_limiter,
+            request_limit: self.request_limit,
+            ramp_up: self.ramp_up,
+            cache_dir: self.cache_dir,
+            instance: self.instance,
+            skipped_builds: Arc::default(),
+        })
+    }
+}
+
+impl DroneClient {
+    /// `instance` decides how the stages and steps of builds fetched from
+    /// `url` are deserialized
+    pub fn builder(
+        instance: DroneInstance,
+        url: Url,
+        repo: RepoSlug,
+        credentials: String,
+    ) -> DroneClientBuilder {
+        DroneClientBuilder {
+            instance,
+            url,
+            repo,
+            credentials,
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: None,
+            request_limit: None,
+            ramp_up: Duration::ZERO,
+            timeout: None,
+            connect_timeout: None,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: None,
+            cache_dir: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            proxy: None,
+            no_proxy: false,
+            ca_cert: None,
+            insecure_skip_tls_verify: false,
+            no_compression: false,
         }
     }
 
-    fn get_bgms_build_list_with_page(&self, page: usize) -> DroneBuildList {
-        let response = self
-            .client
-            .get(
-                self.url
-                    .join("/api/repos/BitGo/bitgo-microservices/builds")
-                    .unwrap(),
-            )
-            .query(&[("page", page)])
-            .send()
-            .unwrap()
-            .error_for_status()
+    pub fn new_with_credentials(
+        instance: DroneInstance,
+        url: Url,
+        repo: RepoSlug,
+        credentials: String,
+    ) -> Result<Self, DroneError> {
+        Self::builder(instance, url, repo, credentials).build()
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub fn repo(&self) -> &RepoSlug {
+        &self.repo
+    }
+
+    pub fn instance(&self) -> DroneInstance {
+        self.instance
+    }
+
+    /// How many builds `get_build_info_batch` has left out so far
+    pub fn skipped_builds(&self) -> usize {
+        self.skipped_builds.load(Ordering::Relaxed)
+    }
+
+    /// `path` under `/api/repos/{owner}/{name}/` on this Drone instance
+    fn repo_url(&self, path: &str) -> Url {
+        self.url
+            .join(&format!("/api/repos/{}/{path}", self.repo))
             .unwrap()
+    }
+
+    /// Sends an idempotent request and deserializes its JSON response
+    fn send<T: DeserializeOwned>(
+        &self,
+        request: DroneRequest,
+        request_builder: RequestBuilder,
+    ) -> Result<T, DroneError> {
+        self.send_with(request, request_builder, |response| {
+            deserialize(request, response)
+        })
+        .map(|(response, _)| response)
+    }
+
+    /// Sends an idempotent request and parses its response with `parse`,
+    /// retrying transient failures according to the client's `RetryPolicy`;
+    /// the raw response is returned along with what it parsed to
+    fn send_with<T>(
+        &self,
+        request: DroneRequest,
+        request_builder: RequestBuilder,
+        parse: impl Fn(&[u8]) -> Result<T, DroneError>,
+    ) -> Result<(T, Bytes), DroneError> {
+        let mut retry = 0;
+        loop {
+            let attempt = request_builder
+                .try_clone()
+                .expect("GET requests have no streaming body and can always be cloned");
+            let parsed = self
+                .send_once(request, attempt)
+                .and_then(|response| Ok((parse(&response)?, response)));
+            match parsed {
+                Err(error) if error.is_retryable() && retry < self.retry_policy.max_retries => {
+                    let delay = self.retry_policy.delay_after(&error, retry);
+                    warn!("{error}; retrying in {}ms", delay.as_millis());
+                    thread::sleep(delay);
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn send_once(
+        &self,
+        request: DroneRequest,
+        request_builder: RequestBuilder,
+    ) -> Result<Bytes, DroneError> {
+        let _permit = self.request_limit.as_ref().map(RequestLimit::acquire);
+        let response = self.send_checked(request, request_builder)?;
+        let url = response.url().clone();
+        response
             .bytes()
-            .unwrap();
-        serde_json::from_slice(&response).unwrap()
+            .map_err(|source| DroneError::transport(request, url, source))
     }
 
-    #[allow(dead_code)]
-    pub fn get_recent_builds(&self) -> DroneBuildList {
-        self.get_bgms_build_list_with_page(1)
+    /// Sends a request once, turning rate limits and error statuses into
+    /// errors while leaving the body unread
+    fn send_checked(
+        &self,
+        request: DroneRequest,
+        request_builder: RequestBuilder,
+    ) -> Result<Response, DroneError> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire();
+        }
+        let response = request_builder.send().map_err(|source| {
+            let url = source.url().cloned().unwrap_or_else(|| self.url.clone());
+            DroneError::transport(request, url, source)
+        })?;
+        let url = response.url().clone();
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(DroneError::RateLimited {
+                request,
+                url,
+                retry_after: retry_after(response.headers()),
+            });
+        }
+        if status.is_client_error() || status.is_server_error() {
+            // the body only adds detail, so failing to read it isn't an error
+            let message = response.text().ok().and_then(|body| error_message(&body));
+            return Err(DroneError::Status {
+                request,
+                url: Box::new(url),
+                status,
+                message,
+            });
+        }
+        Ok(response)
+    }
+
+    fn get_build_list(
+        &self,
+        page: PageRequest,
+        per_page: usize,
+    ) -> Result<DroneBuildList, DroneError> {
+        let request = page.drone_request();
+        debug!("Fetching {request} from '{}'", self.url);
+        self.send(
+            request,
+            self.client
+                .get(self.repo_url("builds"))
+                .query(&page.query(per_page)),
+        )
+    }
+
+    pub fn get_recent_builds(&self) -> Result<DroneBuildList, DroneError> {
+        self.get_build_list(PageRequest::Page(1), MAX_PAGE_SIZE)
+    }
+
+    /// Total number of builds of the repository, as reported by the
+    /// `X-Total-Count` header of the build list; `None` on deployments that
+    /// don't send it. Meant as a quick sanity check, so it isn't retried.
+    pub fn get_build_count(&self) -> Result<Option<u64>, DroneError> {
+        let page = PageRequest::Page(1);
+        let request = page.drone_request();
+        debug!("Fetching the build count from '{}'", self.url);
+        let _permit = self.request_limit.as_ref().map(RequestLimit::acquire);
+        // only the header is read, so a single-build page keeps the body small
+        let response = self.send_checked(
+            request,
+            self.client
+                .get(self.repo_url("builds"))
+                .query(&page.query(1)),
+        )?;
+        let build_count = response
+            .headers()
+            .get(TOTAL_COUNT)
+            .and_then(|total_count| total_count.to_str().ok())
+            .and_then(|total_count| total_count.trim().parse().ok());
+        Ok(build_count)
     }
 
     pub fn get_builds_paginated<'drone>(&'drone self) -> DroneBuildsPaginator<'drone> {
         DroneBuildsPaginator {
-            page: 1,
             drone: self,
-            cached: DroneBuildList::with_capacity(50),
+            pages: BuildPages::new(),
+        }
+    }
+
+    /// Drone's build list can't be filtered by commit, so this scans the whole
+    /// list, up to `max_builds`, for builds whose sha starts with `sha`. A
+    /// restarted build is listed as a new build at the top, so the builds of
+    /// a commit can be any number of pages apart.
+    pub fn get_builds_for_commit(
+        &self,
+        sha: &str,
+        max_builds: usize,
+    ) -> Result<Vec<DroneBuildListItem>, DroneError> {
+        let mut builds = Vec::new();
+        for drone_build_list_item in self.get_builds_paginated().max_builds(max_builds) {
+            let drone_build_list_item = drone_build_list_item?;
+            if drone_build_list_item.git_metadata.git_sha.starts_with(sha) {
+                builds.push(drone_build_list_item);
+            }
+        }
+        Ok(builds)
+    }
+
+    /// Fetches the details of a build, going through the on-disk cache when
+    /// one is configured
+    pub fn get_build_info(&self, build_number: u32) -> Result<DroneBuildInfo, DroneError> {
+        let request = DroneRequest::BuildInfo { build_number };
+        let cache_path = self.cache_path(build_number);
+        if let Some(cache_path) = &cache_path {
+            match fs::read(cache_path) {
+                Ok(cached) => match deserialize_build_info(self.instance, request, &cached) {
+                    Ok(drone_build_info) => return Ok(drone_build_info),
+                    Err(error) => warn!("Ignoring cached '{}': {error}", cache_path.display()),
+                },
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => warn!("Failed to read '{}': {error}", cache_path.display()),
+            }
+        }
+
+        let (drone_build_info, response) = self.send_with(
+            request,
+            self.client
+                .get(self.repo_url(&format!("builds/{build_number}"))),
+            |response| deserialize_build_info(self.instance, request, response),
+        )?;
+
+        // builds that haven't finished will still change, so only finished
+        // builds are safe to cache
+        if let (Some(cache_path), true) = (cache_path, drone_build_info.is_finished()) {
+            let written = cache_path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|()| fs::write(&cache_path, &response));
+            if let Err(error) = written {
+                warn!("Failed to cache '{}': {error}", cache_path.display());
+            }
         }
+        Ok(drone_build_info)
+    }
+
+    /// Fetches the details of every distinct build in `build_numbers` using at
+    /// most `concurrency` requests at a time, calling `on_fetched` after each.
+    /// Builds without the shape of this instance's builds are left out with a
+    /// warning and counted in `skipped_builds`
+    pub fn get_build_info_batch(
+        &self,
+        build_numbers: &[u32],
+        concurrency: usize,
+        on_fetched: impl Fn() + Sync,
+    ) -> Result<HashMap<u32, DroneBuildInfo>, DroneError> {
+        let mut seen = HashSet::new();
+        let build_numbers: Vec<u32> = build_numbers
+            .iter()
+            .copied()
+            .filter(|build_number| seen.insert(*build_number))
+            .collect();
+        let next_index = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let mut drone_build_infos: Vec<Option<Result<DroneBuildInfo, DroneError>>> =
+            build_numbers.iter().map(|_| None).collect();
+
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..concurrency.min(build_numbers.len()))
+                .map(|_| {
+                    scope.spawn(|| {
+                        if !self.ramp_up.is_zero() {
+                            thread::sleep(self.ramp_up.mul_f64(rand::random::<f64>()));
+                        }
+                        let mut fetched = Vec::new();
+                        // stop picking up new builds as soon as any worker has
+                        // failed, since the whole batch is discarded on error
+                        while !failed.load(Ordering::Relaxed) {
+                            let index = next_index.fetch_add(1, Ordering::Relaxed);
+                            let Some(build_number) = build_numbers.get(index) else {
+                                break;
+                            };
+                            let drone_build_info = self.get_build_info(*build_number);
+                            on_fetched();
+                            match drone_build_info {
+                                // one odd build shouldn't cost the whole report
+                                Err(error @ DroneError::UnexpectedShape { .. }) => {
+                                    warn!("Skipping build: {error}");
+                                    self.skipped_builds.fetch_add(1, Ordering::Relaxed);
+                                    continue;
+                                }
+                                Err(_) => failed.store(true, Ordering::Relaxed),
+                                Ok(_) => {}
+                            }
+                            fetched.push((index, drone_build_info));
+                        }
+                        fetched
+                    })
+                })
+                .collect();
+            for worker in workers {
+                for (index, drone_build_info) in worker.join().unwrap() {
+                    drone_build_infos[index] = Some(drone_build_info);
+                }
+            }
+        });
+
+        // on failure some builds were never fetched; the first error
+        // encountered in build order is reported
+        build_numbers
+            .into_iter()
+            .zip(drone_build_infos)
+            .filter_map(|(build_number, drone_build_info)| {
+                Some(drone_build_info?.map(|drone_build_info| (build_number, drone_build_info)))
+            })
+            .collect()
+    }
+
+    fn cache_path(&self, build_number: u32) -> Option<PathBuf> {
+        let cache_dir = self.cache_dir.as_deref()?;
+        Some(build_cache_path(
+            cache_dir,
+            &self.url,
+            &self.repo,
+            build_number,
+        ))
+    }
+
+    /// The repositories the token's user can access. Drone returns all of
+    /// them at once, so this is a single request.
+    pub fn list_repos(&self) -> Result<Vec<RepoInfo>, DroneError> {
+        debug!("Fetching repository list from '{}'", self.url);
+        self.send(
+            DroneRequest::UserRepos,
+            self.client.get(self.url.join("/api/user/repos").unwrap()),
+        )
+    }
+
+    /// The repository this client compares builds for
+    pub fn get_repo(&self) -> Result<RepoInfo, DroneError> {
+        debug!("Fetching repository '{}' from '{}'", self.repo, self.url);
+        self.send(
+            DroneRequest::Repo,
+            self.client
+                .get(self.url.join(&format!("/api/repos/{}", self.repo)).unwrap()),
+        )
+    }
+
+    /// Restarts a finished build, returning the newly queued build. This is
+    /// not idempotent, so it is never retried.
+    pub fn restart_build(&self, build_number: u32) -> Result<DroneBuildInfo, DroneError> {
+        let request = DroneRequest::RestartBuild { build_number };
+        let restarted = self.send_once(
+            request,
+            self.client
+                .post(self.repo_url(&format!("builds/{build_number}"))),
+        );
+        match restarted {
+            Err(DroneError::Status {
+                status: StatusCode::FORBIDDEN,
+                url,
+                ..
+            }) => Err(DroneError::Forbidden { request, url: *url }),
+            restarted => deserialize_build_info(self.instance, request, &restarted?),
+        }
+    }
+
+    /// Fetches the log of a single step; `None` when Drone has already
+    /// purged the logs of the build
+    pub fn get_step_logs(
+        &self,
+        build_number: u32,
+        stage_number: u32,
+        step_number: u32,
+    ) -> Result<Option<Vec<LogLine>>, DroneError> {
+        let logs = self.send(
+            DroneRequest::StepLogs {
+                build_number,
+                stage_number,
+                step_number,
+            },
+            self.client.get(self.repo_url(&format!(
+                "builds/{build_number}/logs/{stage_number}/{step_number}"
+            ))),
+        );
+        match logs {
+            Err(DroneError::Status {
+                status: StatusCode::NOT_FOUND,
+                ..
+            }) => Ok(None),
+            logs => logs.map(Some),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DroneBuildsPaginator<'drone> {
+    drone: &'drone DroneClient,
+    pages: BuildPages,
+}
+
+/// Paging state of a walk over the build list, shared by the blocking and
+/// async paginators
+#[derive(Debug, Clone)]
+struct BuildPages {
+    page: usize,
+    per_page: usize,
+    cached: DroneBuildList,
+    remaining: Option<usize>,
+    /// Set once Drone returns an empty page, i.e. there are no older builds,
+    /// or `since_build` is reached
+    exhausted: bool,
+    pages_fetched: usize,
+    since_build: Option<u32>,
+    pagination: Pagination,
+    /// Smallest build id seen so far, the cursor of `Pagination::Cursor`
+    min_id: Option<u32>,
+}
+
+/// The build list page a paginator asks Drone for next
+#[derive(Debug, Clone, Copy)]
+enum PageRequest {
+    Page(usize),
+    /// The builds listed after the one with this id, i.e. older ones
+    Before(u32),
+}
+
+impl PageRequest {
+    fn drone_request(self) -> DroneRequest {
+        match self {
+            PageRequest::Page(page) => DroneRequest::BuildList { page },
+            PageRequest::Before(before) => DroneRequest::BuildListBefore { before },
+        }
+    }
+
+    fn query(self, per_page: usize) -> [(&'static str, usize); 2] {
+        match self {
+            PageRequest::Page(page) => [("page", page), ("per_page", per_page)],
+            PageRequest::Before(before) => [("before", before as usize), ("per_page", per_page)],
+        }
+    }
+}
+
+impl BuildPages {
+    fn new() -> Self {
+        BuildPages {
+            page: 1,
+            per_page: MAX_PAGE_SIZE,
+            cached: DroneBuildList::with_capacity(MAX_PAGE_SIZE),
+            remaining: None,
+            exhausted: false,
+            pages_fetched: 0,
+            since_build: None,
+            pagination: Pagination::Page,
+            min_id: None,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.remaining == Some(0) || self.exhausted
+    }
+
+    /// The page to fetch before the next build can be yielded, if any
+    fn next_request(&self) -> Option<PageRequest> {
+        if self.is_done() || !self.cached.is_empty() {
+            return None;
+        }
+        Some(match (self.pagination, self.min_id) {
+            (Pagination::Cursor, Some(min_id)) => PageRequest::Before(min_id),
+            _ => PageRequest::Page(self.page),
+        })
+    }
+
+    fn add_page(&mut self, builds: DroneBuildList) {
+        self.cached.extend(builds);
+        self.min_id = self
+            .cached
+            .iter()
+            .map(|build| build.id)
+            .chain(self.min_id)
+            .min();
+        self.page += 1;
+        self.pages_fetched += 1;
+        if self.cached.is_empty() {
+            self.exhausted = true;
+        }
+    }
+
+    fn next_build(&mut self) -> Option<DroneBuildListItem> {
+        if self.is_done() {
+            return None;
+        }
+        let drone_build_list_item = self.cached.pop_front()?;
+        if self
+            .since_build
+            .is_some_and(|since_build| drone_build_list_item.number <= since_build)
+        {
+            self.exhausted = true;
+            return None;
+        }
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= 1;
+        }
+        Some(drone_build_list_item)
+    }
+
+    fn skip_pages(&mut self, pages: usize) {
+        if pages > 0 {
+            self.cached.clear();
+            self.page += pages;
+        }
+    }
+
+    fn per_page(&mut self, per_page: usize) {
+        self.per_page = per_page.clamp(1, MAX_PAGE_SIZE);
+        self.cached = DroneBuildList::with_capacity(self.per_page);
+    }
+}
+
+/// How `DroneBuildsPaginator` asks Drone for the next page of builds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Pagination {
+    /// Numbered pages; builds created during the walk shift later pages,
+    /// which leads to duplicates
+    Page,
+    /// The builds before the smallest build id seen so far, which is stable
+    /// while new builds arrive
+    Cursor,
+}
+
+impl DroneBuildsPaginator<'_> {
+    pub fn skip_pages(mut self, pages: usize) -> Self {
+        self.skip_pages_mut(pages);
+        self
+    }
+
+    pub fn skip_pages_mut(&mut self, pages: usize) -> &mut Self {
+        self.pages.skip_pages(pages);
+        self
+    }
+
+    /// How many build list pages have been requested from Drone so far
+    pub fn pages_fetched(&self) -> usize {
+        self.pages.pages_fetched
+    }
+
+    /// Number of builds requested per page, at most `MAX_PAGE_SIZE`
+    pub fn per_page(mut self, per_page: usize) -> Self {
+        self.per_page_mut(per_page);
+        self
+    }
+
+    pub fn per_page_mut(&mut self, per_page: usize) -> &mut Self {
+        self.pages.per_page(per_page);
+        self
+    }
+
+    /// Stops the paginator after it has yielded `max_builds` builds
+    pub fn max_builds(mut self, max_builds: usize) -> Self {
+        self.max_builds_mut(max_builds);
+        self
+    }
+
+    pub fn max_builds_mut(&mut self, max_builds: usize) -> &mut Self {
+        self.pages.remaining = Some(max_builds);
+        self
     }
 
-    pub fn get_build_info(&self, build_number: u32) -> DroneBuildInfo {
-        let response = self
-            .client
-            .get(
-                self.url
-                    .join("/api/repos/BitGo/bitgo-microservices/builds/")
-                    .unwrap()
-                    .join(&build_number.to_string())
-                    .unwrap(),
-            )
-            // .get(format!(
-            //     "{}/api/repos/BitGo/bitgo-microservices/builds/{build_number}",
-            //     self.url
-            // ))
-            .send()
-            .unwrap()
-            .error_for_status()
-            .unwrap()
-            .bytes()
-            .unwrap();
-        serde_json::from_slice(&response)
-            .unwrap_or_else(|error| panic!("Build Number: {build_number} ; Error: {error}"))
+    pub fn pagination(mut self, pagination: Pagination) -> Self {
+        self.pagination_mut(pagination);
+        self
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct DroneBuildsPaginator<'drone> {
-    page: usize,
-    drone: &'drone DroneClient,
-    cached: DroneBuildList,
-}
+    pub fn pagination_mut(&mut self, pagination: Pagination) -> &mut Self {
+        self.pages.pagination = pagination;
+        self
+    }
 
-impl DroneBuildsPaginator<'_> {
-    #[allow(dead_code)]
-    pub fn skip_pages(mut self, pages: usize) -> Self {
-        self.skip_pages_mut(pages);
+    /// Stops the paginator at the first build numbered `since_build` or
+    /// lower; builds are listed newest first, so all later ones are older
+    pub fn since_build(mut self, since_build: u32) -> Self {
+        self.since_build_mut(since_build);
         self
     }
 
-    #[allow(dead_code)]
-    pub fn skip_pages_mut(&mut self, pages: usize) -> &mut Self {
-        if pages > 0 {
-            self.cached.clear();
-            self.page += pages;
-        }
+    pub fn since_build_mut(&mut self, since_build: u32) -> &mut Self {
+        self.pages.since_build = Some(since_build);
         self
     }
 }
 
 impl Iterator for DroneBuildsPaginator<'_> {
-    type Item = DroneBuildListItem;
+    type Item = Result<DroneBuildListItem, DroneError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.cached.is_empty() {
-            self.cached
-                .extend(self.drone.get_bgms_build_list_with_page(self.page));
-            self.page += 1;
+        if let Some(page) = self.pages.next_request() {
+            match self.drone.get_build_list(page, self.pages.per_page) {
+                Ok(builds) => self.pages.add_page(builds),
+                Err(error) => return Some(Err(error)),
+            }
         }
-        self.cached.pop_front()
+        self.pages.next_build().map(Ok)
     }
 }
 
 use derive_more::{AsMut, AsRef, Deref, DerefMut, IntoIterator};
 use derive_new::new;
 
-#[derive(Debug, Deserialize, Clone, AsRef, AsMut, Deref, DerefMut, IntoIterator, new)]
+#[derive(
+    Debug, Serialize, Deserialize, Clone, AsRef, AsMut, Deref, DerefMut, IntoIterator, new,
+)]
 pub struct DroneBuildList(#[new(default)] VecDeque<DroneBuildListItem>);
 
 impl DroneBuildList {
@@ -130,7 +1290,7 @@ impl DroneBuildList {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DroneBuildListItem {
     pub id: u32,
     pub repo_id: u32,
@@ -138,7 +1298,7 @@ pub struct DroneBuildListItem {
     pub number: u32,
     pub status: DroneStatus,
     pub event: DroneEvent,
-    pub action: String,
+    pub action: DroneAction,
     pub link: Url,
     pub timestamp: u64,
     pub message: String,
@@ -155,7 +1315,18 @@ pub struct DroneBuildListItem {
     pub version: u32,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// A repository listed by `DroneClient::list_repos` or fetched by
+/// `DroneClient::get_repo`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RepoInfo {
+    /// `owner/name`, as passed to --repo
+    pub slug: String,
+    /// Whether the repository is enabled on the Drone instance; inactive
+    /// ones have no builds
+    pub active: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DroneGitMetadata {
     #[serde(rename = "before")]
     pub prev_git_sha: String,
@@ -165,16 +1336,31 @@ pub struct DroneGitMetadata {
     pub git_ref: String,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+/// Which kind of build is compared between the two Drone instances
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BuildMode {
+    /// Builds of pull requests
+    PullRequest,
+    /// Builds of pushes to develop
+    Develop,
+    /// Builds of pushed tags, e.g. release pipelines
+    Tag,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum DroneAction {
     Create,
     Sync,
     #[serde(rename = "")]
     None,
+    /// Any action this tool doesn't know of, e.g. one added by a newer
+    /// Drone; serialized as `unknown`, like `DroneStatus::Other`
+    #[serde(other)]
+    Unknown,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
 #[serde(rename_all = "lowercase")]
 pub enum DroneStatus {
     Success,
@@ -184,37 +1370,86 @@ pub enum DroneStatus {
     Running,
     Skipped,
     Pending,
+    /// Any status this tool doesn't know of; serialized as `other`, so the
+    /// original status is lost when a build is written back out
     #[serde(other)]
+    #[clap(skip)]
     Other,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+impl DroneStatus {
+    /// Folds the status into pass or fail: failure, killed and error become
+    /// `Failure`, success and skipped become `Success`; running, pending and
+    /// unknown statuses are neither and are kept as they are
+    pub fn normalize(self) -> Self {
+        match self {
+            DroneStatus::Failure | DroneStatus::Killed | DroneStatus::Error => DroneStatus::Failure,
+            DroneStatus::Success | DroneStatus::Skipped => DroneStatus::Success,
+            DroneStatus::Running | DroneStatus::Pending | DroneStatus::Other => self,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum DroneEvent {
     PullRequest,
     Push,
     Tag,
+    /// Serialized as `other`, like `DroneStatus::Other`
     #[serde(other)]
     Other,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// A Drone timestamp in seconds since the Unix epoch. Drone reports unset
+/// timestamps (e.g. `finished` of a build that never ran) as 0, so anything
+/// that isn't positive is treated as missing rather than a point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DroneTime(pub i64);
+
+impl DroneTime {
+    pub fn is_set(self) -> bool {
+        self.0 > 0
+    }
+
+    /// `None` when unset
+    pub fn to_system_time(self) -> Option<SystemTime> {
+        let seconds = u64::try_from(self.0).ok().filter(|_| self.is_set())?;
+        Some(UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+
+    /// Seconds from `earlier` to this time, negative when `earlier` is the
+    /// later one; `None` unless both are set
+    pub fn seconds_since(self, earlier: DroneTime) -> Option<i64> {
+        (self.is_set() && earlier.is_set()).then(|| self.0 - earlier.0)
+    }
+
+    /// Time from `earlier` to this time; `None` unless both are set and
+    /// `earlier` is no later than this time
+    pub fn duration_since(self, earlier: DroneTime) -> Option<Duration> {
+        let seconds = u64::try_from(self.seconds_since(earlier)?).ok()?;
+        Some(Duration::from_secs(seconds))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DroneBuildTimestamps {
-    pub started: i64,
-    pub finished: i64,
-    pub created: i64,
-    pub updated: i64,
+    pub started: DroneTime,
+    pub finished: DroneTime,
+    pub created: DroneTime,
+    pub updated: DroneTime,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DroneStageTimestamps {
-    pub started: i64,
-    pub stopped: i64,
-    pub created: i64,
-    pub updated: i64,
+    pub started: DroneTime,
+    pub stopped: DroneTime,
+    pub created: DroneTime,
+    pub updated: DroneTime,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DroneBuildAuthorData {
     pub author_login: String,
     pub author_name: String,
@@ -222,42 +1457,123 @@ pub struct DroneBuildAuthorData {
     pub author_avatar: Url,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DroneBuildInfo {
     #[serde(flatten)]
     pub build_info: DroneBuildListItem,
     pub stages: Vec<DroneStage>,
 }
 
+/// A build without its stages, for reports that only need what the build
+/// list holds
+impl From<DroneBuildListItem> for DroneBuildInfo {
+    fn from(build_info: DroneBuildListItem) -> Self {
+        DroneBuildInfo {
+            build_info,
+            stages: Vec::new(),
+        }
+    }
+}
+
 impl DroneBuildInfo {
+    /// Whether the build is done changing, i.e. neither running nor pending
+    pub fn is_finished(&self) -> bool {
+        !matches!(
+            self.build_info.status,
+            DroneStatus::Running | DroneStatus::Pending
+        )
+    }
+
+    /// Seconds from the build starting to finishing; `None` until it has
+    /// done both
+    pub fn elapsed_time(&self) -> Option<i64> {
+        let timestamps = &self.build_info.timestamps;
+        timestamps.finished.seconds_since(timestamps.started)
+    }
+
     pub fn get_pr_url(&self) -> Url {
         self.build_info.link.clone()
     }
-    pub fn get_pr_number(&self) -> String {
-        self.build_info
-            .link
-            .path_segments()
-            .unwrap()
-            .last()
-            .unwrap()
-            .split('.')
-            .next()
-            .unwrap()
-            .to_string()
+    /// Parsed from links ending like `.../123.diff`; `None` for links of
+    /// push and tag builds, which point at commits, compare views or tags
+    /// such as `.../tree/1.2.3` instead
+    pub fn get_pr_number(&self) -> Option<String> {
+        let last_segment = self.build_info.link.path_segments()?.next_back()?;
+        let pr_number = last_segment.strip_suffix(".diff").unwrap_or(last_segment);
+        (!pr_number.is_empty() && pr_number.bytes().all(|byte| byte.is_ascii_digit()))
+            .then(|| pr_number.to_string())
+    }
+
+    /// Stages and steps are deserialized untagged, so a Drone1 stage carrying
+    /// the extra Drone2 fields would parse as a Drone2 stage. This settles
+    /// every stage and step on the variant of `instance`, dropping the extra
+    /// fields of Drone1 builds; `None` when a Drone2 stage has steps that
+    /// lack them.
+    pub(crate) fn for_instance(mut self, instance: DroneInstance) -> Option<Self> {
+        self.stages = self
+            .stages
+            .into_iter()
+            .map(|stage| stage.for_instance(instance))
+            .collect::<Option<_>>()?;
+        Some(self)
+    }
+
+    /// Stage dependencies as declared by `depends_on`; Drone1 stages don't
+    /// declare any
+    pub fn stage_graph(&self) -> StageGraph {
+        let stages = self
+            .stages
+            .iter()
+            .map(|stage| {
+                let (drone_stage, depends_on) = match stage {
+                    DroneStage::Drone1Stage(stage) => (stage, None),
+                    DroneStage::Drone2Stage(stage) => {
+                        (&stage.drone_stage, stage.depends_on.as_ref())
+                    }
+                };
+                GraphStage {
+                    name: drone_stage.name.clone(),
+                    elapsed_time: drone_stage
+                        .timestamps
+                        .stopped
+                        .duration_since(drone_stage.timestamps.started)
+                        .map_or(0, |elapsed_time| elapsed_time.as_secs() as i64),
+                    depends_on: depends_on.cloned().unwrap_or_default(),
+                }
+            })
+            .collect();
+        StageGraph {
+            build_number: self.build_info.number,
+            stages,
+        }
     }
 
     pub fn get_stage(&self, stage_name: &str) -> Option<&DroneStage> {
+        self.get_stage_of_type(stage_name, None)
+    }
+
+    /// Like `get_stage`, but only considering stages of `stage_type` when
+    /// it's given, see `DroneStage::is_type`
+    pub fn get_stage_of_type(
+        &self,
+        stage_name: &str,
+        stage_type: Option<&str>,
+    ) -> Option<&DroneStage> {
         self.stages
             .iter()
-            .filter(|stage| match stage {
-                DroneStage::Drone1Stage(stage) => stage_name == stage.name,
-                DroneStage::Drone2Stage(stage) => stage_name == stage.drone_stage.name,
-            })
-            .next()
+            .filter(|stage| stage.is_type(stage_type))
+            .find(|stage| stage.get_name() == stage_name)
+    }
+
+    /// Unlike its name, a stage's number is unique within the build
+    pub fn get_stage_by_number(&self, stage_number: u32) -> Option<&DroneStage> {
+        self.stages
+            .iter()
+            .find(|stage| stage.get_number() == stage_number)
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum DroneStage {
     Drone2Stage(Drone2Stage),
@@ -265,22 +1581,96 @@ pub enum DroneStage {
 }
 
 impl DroneStage {
-    pub fn get_step(&self, step_name: &str) -> Option<&DroneStep> {
-        let drone_steps: &Vec<DroneStep> = match self {
+    fn for_instance(self, instance: DroneInstance) -> Option<Self> {
+        match (instance, self) {
+            // the pipeline type is kept, so --stage-type applies to Drone1
+            // stages that have one
+            (DroneInstance::Drone1, DroneStage::Drone2Stage(stage)) => {
+                DroneStage::Drone1Stage(Drone1Stage {
+                    stage_type: Some(stage.stage_type),
+                    ..stage.drone_stage
+                })
+                .for_instance(DroneInstance::Drone1)
+            }
+            // some Drone2 builds still have stages in the legacy Drone1 shape,
+            // whose steps are Drone1-shaped as well
+            (DroneInstance::Drone2, DroneStage::Drone1Stage(stage)) => {
+                DroneStage::Drone1Stage(stage).for_instance(DroneInstance::Drone1)
+            }
+            (_, mut stage) => {
+                let steps = match &mut stage {
+                    DroneStage::Drone1Stage(stage) => &mut stage.steps,
+                    DroneStage::Drone2Stage(stage) => &mut stage.drone_stage.steps,
+                };
+                *steps = mem::take(steps)
+                    .into_iter()
+                    .map(|step| step.for_instance(instance))
+                    .collect::<Option<_>>()?;
+                Some(stage)
+            }
+        }
+    }
+
+    pub fn get_number(&self) -> u32 {
+        match self {
+            DroneStage::Drone1Stage(stage) => stage.number,
+            DroneStage::Drone2Stage(stage) => stage.drone_stage.number,
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            DroneStage::Drone1Stage(stage) => &stage.name,
+            DroneStage::Drone2Stage(stage) => &stage.drone_stage.name,
+        }
+    }
+
+    pub fn get_status(&self) -> DroneStatus {
+        match self {
+            DroneStage::Drone1Stage(stage) => stage.status,
+            DroneStage::Drone2Stage(stage) => stage.drone_stage.status,
+        }
+    }
+
+    /// Pipeline type of the stage, e.g. `docker` or `kubernetes`; stages of
+    /// Drone deployments that don't send one, like legacy stages in Drone2
+    /// builds, have none
+    pub fn get_type(&self) -> Option<&str> {
+        match self {
+            DroneStage::Drone1Stage(stage) => stage.stage_type.as_deref(),
+            DroneStage::Drone2Stage(stage) => Some(&stage.stage_type),
+        }
+    }
+
+    /// Whether the stage is of `stage_type`; `None` matches every stage, and
+    /// so does a stage without a type, which there's nothing to compare with
+    pub fn is_type(&self, stage_type: Option<&str>) -> bool {
+        stage_type.is_none_or(|stage_type| self.get_type().is_none_or(|own| own == stage_type))
+    }
+
+    pub fn get_steps(&self) -> &[DroneStep] {
+        match self {
             DroneStage::Drone1Stage(stage) => &stage.steps,
             DroneStage::Drone2Stage(stage) => &stage.drone_stage.steps,
-        };
-        drone_steps
+        }
+    }
+
+    pub fn get_step(&self, step_name: &str) -> Option<&DroneStep> {
+        self.get_steps().iter().find(|step| match step {
+            DroneStep::Drone1Step(step) => step.name == step_name,
+            DroneStep::Drone2Step(step) => step.drone_step.name == step_name,
+        })
+    }
+
+    /// Unlike its name, a step's number is unique within the stage
+    pub fn get_step_by_number(&self, step_number: u32) -> Option<&DroneStep> {
+        self.get_steps()
             .iter()
-            .filter(|step| match step {
-                DroneStep::Drone1Step(step) => step.name == step_name,
-                DroneStep::Drone2Step(step) => step.drone_step.name == step_name,
-            })
-            .next()
+            .find(|step| step.get_number() == step_number)
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Drone1Stage {
     pub id: u32,
     pub repo_id: u32,
@@ -300,19 +1690,27 @@ pub struct Drone1Stage {
     pub on_failure: bool,
     #[serde(default)]
     pub steps: Vec<DroneStep>,
+    /// Pipeline type of a Drone2-shaped stage settled as a Drone1 stage; in
+    /// a `Drone2Stage` it's always `None`, as `Drone2Stage::stage_type`
+    /// takes the field
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub stage_type: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Drone2Stage {
     #[serde(flatten)]
     pub drone_stage: Drone1Stage,
     pub kind: String,
     #[serde(rename = "type")]
     pub stage_type: String,
+    /// Left out by Drone for stages without dependencies, and so when
+    /// serialized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub depends_on: Option<Vec<String>>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum DroneStep {
     Drone2Step(Drone2Step),
@@ -320,6 +1718,30 @@ pub enum DroneStep {
 }
 
 impl DroneStep {
+    fn for_instance(self, instance: DroneInstance) -> Option<Self> {
+        match (instance, self) {
+            (DroneInstance::Drone1, DroneStep::Drone2Step(step)) => {
+                Some(DroneStep::Drone1Step(step.drone_step))
+            }
+            (DroneInstance::Drone2, DroneStep::Drone1Step(_)) => None,
+            (_, step) => Some(step),
+        }
+    }
+
+    pub fn get_number(&self) -> u32 {
+        match self {
+            Self::Drone1Step(step) => step.number,
+            Self::Drone2Step(step) => step.drone_step.number,
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            Self::Drone1Step(step) => &step.name,
+            Self::Drone2Step(step) => &step.drone_step.name,
+        }
+    }
+
     pub fn get_status(&self) -> DroneStatus {
         match self {
             Self::Drone1Step(step) => step.status,
@@ -327,28 +1749,31 @@ impl DroneStep {
         }
     }
 
-    pub fn get_started_timestamp(&self) -> i64 {
-        match self {
+    /// `None` for steps that haven't started yet
+    pub fn get_started_timestamp(&self) -> Option<DroneTime> {
+        let started = match self {
             Self::Drone1Step(step) => step.started,
             Self::Drone2Step(step) => step.drone_step.started,
-        }
-        .unwrap()
+        };
+        started.filter(|started| started.is_set())
     }
 
-    pub fn get_stopped_timestamp(&self) -> i64 {
-        match self {
+    /// `None` for steps that are still running or haven't started yet
+    pub fn get_stopped_timestamp(&self) -> Option<DroneTime> {
+        let stopped = match self {
             Self::Drone1Step(step) => step.stopped,
             Self::Drone2Step(step) => step.drone_step.stopped,
-        }
-        .unwrap()
+        };
+        stopped.filter(|stopped| stopped.is_set())
     }
 
-    pub fn elapsed_time(&self) -> i64 {
-        self.get_stopped_timestamp() - self.get_started_timestamp()
+    pub fn elapsed_time(&self) -> Option<i64> {
+        self.get_stopped_timestamp()?
+            .seconds_since(self.get_started_timestamp()?)
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Drone1Step {
     pub id: u32,
     pub step_id: u32,
@@ -357,45 +1782,642 @@ pub struct Drone1Step {
     pub status: DroneStatus,
     pub errignore: Option<bool>,
     pub exit_code: i32,
-    pub started: Option<i64>,
-    pub stopped: Option<i64>,
+    pub started: Option<DroneTime>,
+    pub stopped: Option<DroneTime>,
     pub version: u32,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Drone2Step {
     #[serde(flatten)]
     pub drone_step: Drone1Step,
-    #[serde(default)]
+    /// Left out by Drone for steps without dependencies, and so when
+    /// serialized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub depends_on: Option<Vec<String>>,
     pub image: String,
 }
 
-pub fn wallet_platform_system_status(drone_build_info: &DroneBuildInfo) -> DroneStatus {
-    if let DroneStage::Drone1Stage(_) = drone_build_info.stages.iter().next().unwrap() {
-        panic!("This function only works for drone2 DroneBuildInfos");
+/// `{cache_dir}/{host}/{owner}/{name}/{build_number}.json`, so builds of
+/// different Drone instances and repositories never collide
+fn build_cache_path(cache_dir: &Path, url: &Url, repo: &RepoSlug, build_number: u32) -> PathBuf {
+    let host = match url.port() {
+        Some(port) => format!("{}_{port}", url.host_str().unwrap_or_default()),
+        None => url.host_str().unwrap_or_default().to_string(),
     };
-    use regex::Regex;
-    let re = Regex::new(r"^wallet-platform-.*").unwrap();
+    cache_dir
+        .join(host)
+        .join(&repo.owner)
+        .join(&repo.name)
+        .join(format!("{build_number}.json"))
+}
 
+/// `instance` decides how the stages and steps of the build are read
+fn deserialize_build_info(
+    instance: DroneInstance,
+    request: DroneRequest,
+    response: &[u8],
+) -> Result<DroneBuildInfo, DroneError> {
+    let drone_build_info: DroneBuildInfo = deserialize(request, response)?;
     drone_build_info
-        .stages
-        .iter()
-        .map(|stage| {
-            if let DroneStage::Drone2Stage(stage) = stage {
-                stage
-            } else {
-                panic!("This function only works for drone2 DroneBuildInfos");
+        .for_instance(instance)
+        .ok_or(DroneError::UnexpectedShape { request, instance })
+}
+
+fn deserialize<T: DeserializeOwned>(
+    request: DroneRequest,
+    response: &[u8],
+) -> Result<T, DroneError> {
+    serde_json::from_slice(response).map_err(|source| DroneError::Deserialize { request, source })
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LogLine {
+    pub pos: u32,
+    pub out: String,
+    pub time: i64,
+}
+
+#[derive(Debug, Display, Error)]
+pub enum SystemStatusError {
+    #[display(fmt = "build {} has no stages", build_number)]
+    NoStages {
+        #[error(not(source))]
+        build_number: u32,
+    },
+    #[display(
+        fmt = "stage '{}' of build {} has not finished",
+        stage_name,
+        build_number
+    )]
+    Unfinished {
+        build_number: u32,
+        #[error(not(source))]
+        stage_name: String,
+    },
+}
+
+/// Combined status of the Drone2 stages matching `system_stage_regex`, and
+/// of `stage_type` when it's given: `Success` when each of them succeeded or
+/// was skipped, `Failure` otherwise. That `drone_build_info` is a Drone2
+/// build is up to the caller, who knows which client fetched it; legacy
+/// Drone1-shaped stages count by name and status like any other, and match
+/// every `stage_type` like untyped stages do in `DroneStage::is_type`.
+pub fn wallet_platform_system_status(
+    drone_build_info: &DroneBuildInfo,
+    system_stage_regex: &Regex,
+    stage_type: Option<&str>,
+) -> Result<DroneStatus, SystemStatusError> {
+    let build_number = drone_build_info.build_info.number;
+    if drone_build_info.stages.is_empty() {
+        return Err(SystemStatusError::NoStages { build_number });
+    }
+
+    let mut status = DroneStatus::Success;
+    for stage in &drone_build_info.stages {
+        if !system_stage_regex.is_match(stage.get_name()) || !stage.is_type(stage_type) {
+            continue;
+        }
+        match stage.get_status() {
+            DroneStatus::Success | DroneStatus::Skipped => {}
+            DroneStatus::Running | DroneStatus::Pending => {
+                return Err(SystemStatusError::Unfinished {
+                    build_number,
+                    stage_name: stage.get_name().to_string(),
+                })
             }
+            _ => status = DroneStatus::Failure,
+        }
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::{HttpMockRequest, MockServer, GET};
+
+    const REPO: &str = "owner/repo";
+
+    fn drone1_build(number: u32) -> String {
+        format!(
+            r#"{{"id":{number},"repo_id":1,"trigger":"","number":{number},"status":"success","event":"pull_request","action":"","link":"http://x/pull/{number}.diff","timestamp":0,"message":"","before":"","after":"sha{number}","ref":"","source_repo":"","source":"","target":"","author_login":"x","author_name":"","author_email":"","author_avatar":"http://x/","sender":"","started":1,"finished":2,"created":1,"updated":2,"version":1,"stages":[{{"id":1,"repo_id":1,"build_id":{number},"number":1,"name":"build-pull-request","status":"success","errignore":false,"exit_code":0,"machine":null,"os":"linux","arch":"amd64","started":1,"stopped":2,"created":1,"updated":2,"version":1,"on_success":true,"on_failure":false,"steps":[{{"id":1,"step_id":1,"number":1,"name":"run-wallet-platform-unit-tests","status":"success","errignore":false,"exit_code":0,"started":1,"stopped":2,"version":1}}]}}]}}"#
+        )
+    }
+
+    fn client(instance: DroneInstance, server: &MockServer) -> DroneClientBuilder {
+        DroneClient::builder(
+            instance,
+            Url::parse(&server.base_url()).unwrap(),
+            REPO.parse().unwrap(),
+            "token".to_string(),
+        )
+    }
+
+    fn mock_build<'server>(
+        server: &'server MockServer,
+        build_number: u32,
+        body: &str,
+    ) -> httpmock::Mock<'server> {
+        server.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/api/repos/{REPO}/builds/{build_number}"));
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(body);
         })
-        .filter(|stage| re.is_match(&stage.drone_stage.name))
-        .fold(DroneStatus::Success, |status, stage| match status {
-            DroneStatus::Failure => DroneStatus::Failure,
-            DroneStatus::Success => match stage.drone_stage.status {
-                DroneStatus::Success => DroneStatus::Success,
-                DroneStatus::Skipped => DroneStatus::Success,
-                _ => DroneStatus::Failure,
-            },
-            _ => panic!("status can be nothing other than 'Success' or 'Failure'"),
-        })
+    }
+
+    /// `drone1_build` with the fields Drone2 adds to stages and steps
+    fn drone2_build(number: u32) -> serde_json::Value {
+        let mut build: serde_json::Value = serde_json::from_str(&drone1_build(number)).unwrap();
+        for stage in build["stages"].as_array_mut().unwrap() {
+            stage["kind"] = "pipeline".into();
+            stage["type"] = "kubernetes".into();
+            for step in stage["steps"].as_array_mut().unwrap() {
+                step["image"] = "node:18".into();
+            }
+        }
+        build
+    }
+
+    #[test]
+    fn drone1_builds_with_drone2_fields_settle_on_drone1_stages() {
+        let request = DroneRequest::BuildInfo { build_number: 1 };
+        let build = drone2_build(1).to_string();
+
+        let untagged: DroneBuildInfo = serde_json::from_str(&build).unwrap();
+        assert!(matches!(untagged.stages[0], DroneStage::Drone2Stage(_)));
+
+        let drone_build_info =
+            deserialize_build_info(DroneInstance::Drone1, request, build.as_bytes()).unwrap();
+        let DroneStage::Drone1Stage(stage) = &drone_build_info.stages[0] else {
+            panic!(
+                "expected a Drone1 stage, got {:?}",
+                drone_build_info.stages[0]
+            );
+        };
+        assert_eq!(stage.stage_type.as_deref(), Some("kubernetes"));
+        assert!(matches!(stage.steps[0], DroneStep::Drone1Step(_)));
+    }
+
+    #[test]
+    fn drone2_stages_need_drone2_steps() {
+        let request = DroneRequest::BuildInfo { build_number: 1 };
+        let mut build = drone2_build(1);
+        build["stages"][0]["steps"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("image");
+
+        let error =
+            deserialize_build_info(DroneInstance::Drone2, request, build.to_string().as_bytes())
+                .unwrap_err();
+        assert!(matches!(
+            error,
+            DroneError::UnexpectedShape {
+                instance: DroneInstance::Drone2,
+                ..
+            }
+        ));
+        // on Drone1 the missing field is of no concern
+        deserialize_build_info(DroneInstance::Drone1, request, build.to_string().as_bytes())
+            .unwrap();
+    }
+
+    #[test]
+    fn build_info_batch_skips_builds_of_another_shape() {
+        let server = MockServer::start();
+        for build_number in 1..=3 {
+            let mut build = drone2_build(build_number);
+            if build_number == 2 {
+                build["stages"][0]["steps"][0]
+                    .as_object_mut()
+                    .unwrap()
+                    .remove("image");
+            }
+            mock_build(&server, build_number, &build.to_string());
+        }
+        let drone_client = client(DroneInstance::Drone2, &server).build().unwrap();
+
+        let drone_build_infos = drone_client
+            .get_build_info_batch(&[1, 2, 3], 2, || {})
+            .unwrap();
+
+        let mut build_numbers: Vec<u32> = drone_build_infos.into_keys().collect();
+        build_numbers.sort();
+        assert_eq!(build_numbers, [1, 3]);
+        assert_eq!(drone_client.skipped_builds(), 1);
+    }
+
+    fn build_with_link(link: &str) -> DroneBuildInfo {
+        let mut build: serde_json::Value = serde_json::from_str(&drone1_build(1)).unwrap();
+        build["link"] = link.into();
+        serde_json::from_value(build).unwrap()
+    }
+
+    #[test]
+    fn pr_numbers_come_from_pull_request_links_only() {
+        let pr_number = |link| build_with_link(link).get_pr_number();
+        assert_eq!(
+            pr_number("https://github.com/o/r/pull/312.diff").as_deref(),
+            Some("312")
+        );
+        assert_eq!(
+            pr_number("https://github.com/o/r/pull/312").as_deref(),
+            Some("312")
+        );
+        // push builds link the compare view or the commit
+        assert_eq!(
+            pr_number("https://github.com/o/r/compare/0a1b2c3d4e5f...6a7b8c9d0e1f"),
+            None
+        );
+        assert_eq!(
+            pr_number("https://github.com/o/r/commit/c0ffee12aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"),
+            None
+        );
+        // tag builds link the tag
+        assert_eq!(pr_number("https://github.com/o/r/tree/v1.2.3"), None);
+        assert_eq!(pr_number("https://github.com/o/r/tree/1.2.3"), None);
+        assert_eq!(pr_number("https://github.com/"), None);
+    }
+
+    #[test]
+    fn request_limit_caps_requests_in_flight() {
+        let delay = Duration::from_millis(50);
+        let server = MockServer::start();
+        let build_numbers: Vec<u32> = (1..=24).collect();
+        for &build_number in &build_numbers {
+            server.mock(|when, then| {
+                when.method(GET)
+                    .path(format!("/api/repos/{REPO}/builds/{build_number}"));
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .body(drone1_build(build_number))
+                    .delay(delay);
+            });
+        }
+        let drone_client = client(DroneInstance::Drone1, &server)
+            .request_limit(RequestLimit::new(3))
+            .build()
+            .unwrap();
+
+        let started = Instant::now();
+        let drone_build_infos = drone_client
+            .get_build_info_batch(&build_numbers, 8, || {})
+            .unwrap();
+
+        assert_eq!(drone_build_infos.len(), build_numbers.len());
+        // with 3 requests in flight at a time the 24 are answered in 8 rounds;
+        // all 8 workers' requests at once would take 3
+        let elapsed = started.elapsed();
+        assert!(elapsed >= delay * 8, "24 requests took only {elapsed:?}");
+    }
+
+    #[test]
+    fn unset_timestamps_have_no_system_time() {
+        assert_eq!(DroneTime(0).to_system_time(), None);
+        assert_eq!(DroneTime(-1).to_system_time(), None);
+        assert_eq!(DroneTime(i64::MIN).to_system_time(), None);
+        assert_eq!(
+            DroneTime(1660000000).to_system_time(),
+            Some(UNIX_EPOCH + Duration::from_secs(1660000000))
+        );
+    }
+
+    #[test]
+    fn unset_timestamps_have_no_elapsed_time() {
+        assert_eq!(DroneTime(20).seconds_since(DroneTime(0)), None);
+        assert_eq!(DroneTime(-5).seconds_since(DroneTime(10)), None);
+        assert_eq!(DroneTime(10).seconds_since(DroneTime(20)), Some(-10));
+        assert_eq!(DroneTime(10).duration_since(DroneTime(20)), None);
+        assert_eq!(
+            DroneTime(20).duration_since(DroneTime(10)),
+            Some(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn steps_still_running_have_no_elapsed_time() {
+        let step: DroneStep = serde_json::from_str(
+            r#"{"id":1,"step_id":1,"number":1,"name":"run-wallet-platform-unit-tests","status":"running","errignore":false,"exit_code":0,"started":1660000000,"version":1}"#,
+        )
+        .unwrap();
+        assert_eq!(step.get_started_timestamp(), Some(DroneTime(1660000000)));
+        assert_eq!(step.get_stopped_timestamp(), None);
+        assert_eq!(step.elapsed_time(), None);
+
+        let step: DroneStep = serde_json::from_str(
+            r#"{"id":1,"step_id":1,"number":1,"name":"run-wallet-platform-unit-tests","status":"pending","errignore":false,"exit_code":0,"started":0,"stopped":null,"version":1}"#,
+        )
+        .unwrap();
+        assert_eq!(step.get_started_timestamp(), None);
+        assert_eq!(step.elapsed_time(), None);
+    }
+
+    #[test]
+    fn paginator_stops_at_an_empty_page() {
+        let server = MockServer::start();
+        let mock_page = |page: usize, build_numbers: &[u32]| {
+            let builds: Vec<serde_json::Value> = build_numbers
+                .iter()
+                .map(|&number| {
+                    let mut build: serde_json::Value =
+                        serde_json::from_str(&drone1_build(number)).unwrap();
+                    build.as_object_mut().unwrap().remove("stages");
+                    build
+                })
+                .collect();
+            server.mock(|when, then| {
+                when.method(GET)
+                    .path(format!("/api/repos/{REPO}/builds"))
+                    .query_param("page", page.to_string());
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .body(serde_json::to_string(&builds).unwrap());
+            })
+        };
+        let pages = [
+            mock_page(1, &[4, 3]),
+            mock_page(2, &[2, 1]),
+            mock_page(3, &[]),
+        ];
+        let drone_client = client(DroneInstance::Drone1, &server).build().unwrap();
+
+        let mut paginator = drone_client.get_builds_paginated().per_page(2);
+        let build_numbers: Vec<u32> = paginator
+            .by_ref()
+            .map(|build| build.unwrap().number)
+            .collect();
+
+        assert_eq!(build_numbers, [4, 3, 2, 1]);
+        assert_eq!(paginator.pages_fetched(), 3);
+        assert!(paginator.next().is_none());
+        for page in pages {
+            page.assert();
+        }
+    }
+
+    /// `drone2_build(1)` with a unit test stage and the system stage in
+    /// `system_status`
+    fn build_with_system_stage(system_status: DroneStatus) -> DroneBuildInfo {
+        let mut build = drone2_build(1);
+        let mut system_stage = build["stages"][0].clone();
+        system_stage["number"] = 2.into();
+        system_stage["name"] = "wallet-platform-system".into();
+        system_stage["status"] = serde_json::to_value(system_status).unwrap();
+        build["stages"][0]["name"] = "wallet-platform".into();
+        build["stages"].as_array_mut().unwrap().push(system_stage);
+        deserialize_build_info(
+            DroneInstance::Drone2,
+            DroneRequest::BuildInfo { build_number: 1 },
+            build.to_string().as_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn system_status_fails_on_errored_stages() {
+        let system_stage_regex = Regex::new("^wallet-platform-.*").unwrap();
+        let status = |system_status| {
+            wallet_platform_system_status(
+                &build_with_system_stage(system_status),
+                &system_stage_regex,
+                None,
+            )
+        };
+        assert_eq!(status(DroneStatus::Success).unwrap(), DroneStatus::Success);
+        assert_eq!(status(DroneStatus::Skipped).unwrap(), DroneStatus::Success);
+        for system_status in [
+            DroneStatus::Failure,
+            DroneStatus::Error,
+            DroneStatus::Killed,
+            DroneStatus::Other,
+        ] {
+            assert_eq!(status(system_status).unwrap(), DroneStatus::Failure);
+        }
+    }
+
+    #[test]
+    fn system_status_of_running_stages_is_unknown() {
+        let system_stage_regex = Regex::new("^wallet-platform-.*").unwrap();
+        for system_status in [DroneStatus::Running, DroneStatus::Pending] {
+            let error = wallet_platform_system_status(
+                &build_with_system_stage(system_status),
+                &system_stage_regex,
+                None,
+            )
+            .unwrap_err();
+            assert!(matches!(
+                error,
+                SystemStatusError::Unfinished { build_number: 1, ref stage_name }
+                    if stage_name == "wallet-platform-system"
+            ));
+        }
+        let no_stages =
+            DroneBuildInfo::from(build_with_system_stage(DroneStatus::Success).build_info);
+        assert!(matches!(
+            wallet_platform_system_status(&no_stages, &system_stage_regex, None),
+            Err(SystemStatusError::NoStages { build_number: 1 })
+        ));
+    }
+
+    #[test]
+    fn builds_serialize_back_to_what_was_deserialized() {
+        let drone1_build: serde_json::Value = serde_json::from_str(&drone1_build(1)).unwrap();
+        // a second stage depending on the first, which depends on nothing
+        let mut staged_build = drone2_build(1);
+        let mut second_stage = staged_build["stages"][0].clone();
+        second_stage["number"] = 2.into();
+        second_stage["name"] = "wallet-platform-system".into();
+        second_stage["depends_on"] = serde_json::json!(["build-pull-request"]);
+        staged_build["stages"]
+            .as_array_mut()
+            .unwrap()
+            .push(second_stage);
+        for build in [drone1_build, drone2_build(1), staged_build] {
+            let drone_build_info: DroneBuildInfo = serde_json::from_value(build.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&drone_build_info).unwrap(), build);
+
+            let mut listed = build.clone();
+            listed.as_object_mut().unwrap().remove("stages");
+            let drone_build_list_item: DroneBuildListItem =
+                serde_json::from_value(listed.clone()).unwrap();
+            assert_eq!(
+                serde_json::to_value(&drone_build_list_item).unwrap(),
+                listed
+            );
+        }
+    }
+
+    /// A client retrying right away
+    fn retrying_client(server: &MockServer) -> DroneClient {
+        client(DroneInstance::Drone1, server)
+            .retry_policy(RetryPolicy {
+                max_retries: 3,
+                base_delay: Duration::ZERO,
+                ..Default::default()
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn truncated_responses_are_fetched_again() {
+        // httpmock matchers are plain functions, so whether the first
+        // request was answered is kept in a static
+        static ANSWERED: AtomicBool = AtomicBool::new(false);
+        fn first_request(_: &HttpMockRequest) -> bool {
+            !ANSWERED.swap(true, Ordering::SeqCst)
+        }
+        let build = drone1_build(1);
+        let server = MockServer::start();
+        // the first mock that matches answers, so this one goes first
+        let truncated = server.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/api/repos/{REPO}/builds/1"))
+                .matches(first_request);
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(&build[..build.len() / 2]);
+        });
+        let complete = mock_build(&server, 1, &build);
+
+        let drone_build_info = retrying_client(&server).get_build_info(1).unwrap();
+
+        assert_eq!(drone_build_info.build_info.number, 1);
+        truncated.assert();
+        complete.assert();
+    }
+
+    #[test]
+    fn responses_of_the_wrong_shape_are_not_fetched_again() {
+        let mut build: serde_json::Value = serde_json::from_str(&drone1_build(1)).unwrap();
+        build["number"] = "one".into();
+        let server = MockServer::start();
+        let mock = mock_build(&server, 1, &build.to_string());
+
+        let error = retrying_client(&server).get_build_info(1).unwrap_err();
+
+        assert!(matches!(error, DroneError::Deserialize { .. }), "{error}");
+        assert!(!error.is_retryable());
+        mock.assert();
+    }
+
+    #[test]
+    fn drone2_builds_with_a_legacy_first_stage_have_a_system_status() {
+        // the first stage is Drone1-shaped, without a kind, type or images
+        let mut build = drone2_build(1);
+        let mut legacy_stage: serde_json::Value =
+            serde_json::from_str::<serde_json::Value>(&drone1_build(1)).unwrap()["stages"][0]
+                .clone();
+        legacy_stage["name"] = "wallet-platform-legacy".into();
+        legacy_stage["status"] = "failure".into();
+        build["stages"][0]["number"] = 2.into();
+        build["stages"][0]["name"] = "wallet-platform-system".into();
+        build["stages"]
+            .as_array_mut()
+            .unwrap()
+            .insert(0, legacy_stage);
+
+        let drone_build_info = deserialize_build_info(
+            DroneInstance::Drone2,
+            DroneRequest::BuildInfo { build_number: 1 },
+            build.to_string().as_bytes(),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            drone_build_info.stages[0],
+            DroneStage::Drone1Stage(_)
+        ));
+        assert!(matches!(
+            drone_build_info.stages[1],
+            DroneStage::Drone2Stage(_)
+        ));
+        let system_stage_regex = Regex::new("^wallet-platform-.*").unwrap();
+        let status = |stage_type| {
+            wallet_platform_system_status(&drone_build_info, &system_stage_regex, stage_type)
+                .unwrap()
+        };
+        // the legacy stage counts like any other, whatever the stage type
+        assert_eq!(status(None), DroneStatus::Failure);
+        assert_eq!(status(Some("kubernetes")), DroneStatus::Failure);
+        assert_eq!(status(Some("docker")), DroneStatus::Failure);
+    }
+
+    #[test]
+    fn unknown_enum_values_fall_back_instead_of_failing() {
+        let action = |action: &str| {
+            let mut build: serde_json::Value = serde_json::from_str(&drone1_build(1)).unwrap();
+            build.as_object_mut().unwrap().remove("stages");
+            build["action"] = action.into();
+            serde_json::from_value::<DroneBuildListItem>(build)
+                .unwrap()
+                .action
+        };
+        assert_eq!(action("sync"), DroneAction::Sync);
+        assert_eq!(action(""), DroneAction::None);
+        assert_eq!(action("promote"), DroneAction::Unknown);
+        assert_eq!(
+            serde_json::from_str::<DroneStatus>(r#""waiting_on_dependencies""#).unwrap(),
+            DroneStatus::Other
+        );
+        assert_eq!(
+            serde_json::from_str::<DroneEvent>(r#""cron""#).unwrap(),
+            DroneEvent::Other
+        );
+    }
+
+    #[test]
+    fn normalized_statuses_are_pass_or_fail() {
+        use DroneStatus::*;
+        for (status, normalized) in [
+            (Success, Success),
+            (Skipped, Success),
+            (Failure, Failure),
+            (Killed, Failure),
+            (Error, Failure),
+            (Running, Running),
+            (Pending, Pending),
+            (Other, Other),
+        ] {
+            assert_eq!(status.normalize(), normalized, "{status:?}");
+        }
+    }
+
+    #[test]
+    fn oversized_retry_after_waits_only_up_to_the_max_delay() {
+        let rate_limited = |retry_after| DroneError::RateLimited {
+            request: DroneRequest::BuildInfo { build_number: 1 },
+            url: Url::parse("https://drone.example.com/").unwrap(),
+            retry_after,
+        };
+        let retry_policy = RetryPolicy::default();
+        let a_day = parse_retry_after("86400").unwrap();
+        assert_eq!(a_day, Duration::from_secs(86400));
+        assert_eq!(
+            retry_policy.delay_after(&rate_limited(Some(a_day)), 0),
+            retry_policy.max_delay
+        );
+        let far_future = parse_retry_after("Wed, 21 Oct 2099 07:28:00 GMT").unwrap();
+        assert_eq!(
+            retry_policy.delay_after(&rate_limited(Some(far_future)), 0),
+            retry_policy.max_delay
+        );
+        // waits asked for within the limit are kept as they are
+        assert_eq!(
+            retry_policy.delay_after(&rate_limited(Some(Duration::from_secs(5))), 0),
+            Duration::from_secs(5)
+        );
+        // and so is the backoff
+        let retry_policy = RetryPolicy {
+            base_delay: Duration::from_secs(10),
+            ..RetryPolicy::default()
+        };
+        assert_eq!(
+            retry_policy.delay_after(&rate_limited(None), 10),
+            retry_policy.max_delay
+        );
+    }
 }
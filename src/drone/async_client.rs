@@ -0,0 +1,303 @@
+use super::{
+    build_cache_path, deserialize, deserialize_build_info, error_message, retry_after, BuildPages,
+    DroneBuildInfo, DroneBuildList, DroneBuildListItem, DroneClientBuilder, DroneError,
+    DroneInstance, DroneRequest, PageRequest, Pagination, RateLimiter, RepoSlug, RetryPolicy,
+    MAX_PAGE_SIZE,
+};
+use bytes::Bytes;
+use futures_util::stream::{self, Stream};
+use log::{debug, warn};
+use reqwest::{Client, ClientBuilder, Proxy, RequestBuilder, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use std::io;
+use std::path::PathBuf;
+use tokio::{fs, time};
+
+/// `DroneClient` for async code, whose requests are futures to be run within
+/// a Tokio runtime; retries, rate limiting and the build cache work the same
+#[derive(Debug, Clone)]
+pub struct AsyncDroneClient {
+    client: Client,
+    url: Url,
+    repo: RepoSlug,
+    retry_policy: RetryPolicy,
+    rate_limiter: Option<RateLimiter>,
+    cache_dir: Option<PathBuf>,
+    instance: DroneInstance,
+}
+
+impl DroneClientBuilder {
+    pub fn build_async(self) -> Result<AsyncDroneClient, DroneError> {
+        let mut client_builder = ClientBuilder::new().default_headers(self.default_headers()?);
+        if let Some(timeout) = self.timeout {
+            client_builder = client_builder.timeout(timeout);
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            client_builder = client_builder.connect_timeout(connect_timeout);
+        }
+        if let Some(pool_max_idle_per_host) = self.pool_max_idle_per_host {
+            client_builder = client_builder.pool_max_idle_per_host(pool_max_idle_per_host);
+        }
+        if let Some(pool_idle_timeout) = self.pool_idle_timeout {
+            client_builder = client_builder.pool_idle_timeout(pool_idle_timeout);
+        }
+        if let Some(certificate) = self.ca_certificate()? {
+            client_builder = client_builder.add_root_certificate(certificate);
+        }
+        if self.insecure_skip_tls_verify {
+            client_builder = client_builder.danger_accept_invalid_certs(true);
+        }
+        if self.no_compression {
+            client_builder = client_builder.no_gzip().no_deflate();
+        }
+        if self.no_proxy {
+            client_builder = client_builder.no_proxy();
+        } else if let Some(proxy) = self.proxy {
+            client_builder =
+                client_builder.proxy(Proxy::all(proxy).map_err(DroneError::InvalidProxy)?);
+        }
+        let client = client_builder.build().map_err(DroneError::Client)?;
+        Ok(AsyncDroneClient {
+            client,
+            url: self.url,
+            repo: self.repo,
+            retry_policy: self.retry_policy,
+            rate_limiter: self.rate_limiter,
+            cache_dir: self.cache_dir,
+            instance: self.instance,
+        })
+    }
+}
+
+impl AsyncDroneClient {
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// `path` under `/api/repos/{owner}/{name}/` on this Drone instance
+    fn repo_url(&self, path: &str) -> Url {
+        self.url
+            .join(&format!("/api/repos/{}/{path}", self.repo))
+            .unwrap()
+    }
+
+    /// Sends an idempotent request and deserializes its JSON response
+    async fn send<T: DeserializeOwned>(
+        &self,
+        request: DroneRequest,
+        request_builder: RequestBuilder,
+    ) -> Result<T, DroneError> {
+        self.send_with(request, request_builder, |response| {
+            deserialize(request, response)
+        })
+        .await
+        .map(|(response, _)| response)
+    }
+
+    /// Sends an idempotent request and parses its response with `parse`,
+    /// retrying transient failures according to the client's `RetryPolicy`;
+    /// the raw response is returned along with what it parsed to
+    async fn send_with<T>(
+        &self,
+        request: DroneRequest,
+        request_builder: RequestBuilder,
+        parse: impl Fn(&[u8]) -> Result<T, DroneError>,
+    ) -> Result<(T, Bytes), DroneError> {
+        let mut retry = 0;
+        loop {
+            let attempt = request_builder
+                .try_clone()
+                .expect("GET requests have no streaming body and can always be cloned");
+            let parsed = self
+                .send_once(request, attempt)
+                .await
+                .and_then(|response| Ok((parse(&response)?, response)));
+            match parsed {
+                Err(error) if error.is_retryable() && retry < self.retry_policy.max_retries => {
+                    let delay = self.retry_policy.delay_after(&error, retry);
+                    warn!("{error}; retrying in {}ms", delay.as_millis());
+                    time::sleep(delay).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn send_once(
+        &self,
+        request: DroneRequest,
+        request_builder: RequestBuilder,
+    ) -> Result<Bytes, DroneError> {
+        if let Some(wait) = self
+            .rate_limiter
+            .as_ref()
+            .and_then(|rate_limiter| rate_limiter.reserve())
+        {
+            time::sleep(wait).await;
+        }
+        let response = request_builder.send().await.map_err(|source| {
+            let url = source.url().cloned().unwrap_or_else(|| self.url.clone());
+            DroneError::transport(request, url, source)
+        })?;
+        let url = response.url().clone();
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(DroneError::RateLimited {
+                request,
+                url,
+                retry_after: retry_after(response.headers()),
+            });
+        }
+        if status.is_client_error() || status.is_server_error() {
+            // the body only adds detail, so failing to read it isn't an error
+            let message = response
+                .text()
+                .await
+                .ok()
+                .and_then(|body| error_message(&body));
+            return Err(DroneError::Status {
+                request,
+                url: Box::new(url),
+                status,
+                message,
+            });
+        }
+        response
+            .bytes()
+            .await
+            .map_err(|source| DroneError::transport(request, url, source))
+    }
+
+    async fn get_build_list(
+        &self,
+        page: PageRequest,
+        per_page: usize,
+    ) -> Result<DroneBuildList, DroneError> {
+        let request = page.drone_request();
+        debug!("Fetching {request} from '{}'", self.url);
+        self.send(
+            request,
+            self.client
+                .get(self.repo_url("builds"))
+                .query(&page.query(per_page)),
+        )
+        .await
+    }
+
+    pub async fn get_recent_builds(&self) -> Result<DroneBuildList, DroneError> {
+        self.get_build_list(PageRequest::Page(1), MAX_PAGE_SIZE)
+            .await
+    }
+
+    pub fn get_builds_paginated(&self) -> AsyncDroneBuildsPaginator<'_> {
+        AsyncDroneBuildsPaginator {
+            drone: self,
+            pages: BuildPages::new(),
+        }
+    }
+
+    /// Fetches the details of a build, going through the on-disk cache when
+    /// one is configured
+    pub async fn get_build_info(&self, build_number: u32) -> Result<DroneBuildInfo, DroneError> {
+        let request = DroneRequest::BuildInfo { build_number };
+        let cache_path = self
+            .cache_dir
+            .as_deref()
+            .map(|cache_dir| build_cache_path(cache_dir, &self.url, &self.repo, build_number));
+        if let Some(cache_path) = &cache_path {
+            match fs::read(cache_path).await {
+                Ok(cached) => match deserialize_build_info(self.instance, request, &cached) {
+                    Ok(drone_build_info) => return Ok(drone_build_info),
+                    Err(error) => warn!("Ignoring cached '{}': {error}", cache_path.display()),
+                },
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => warn!("Failed to read '{}': {error}", cache_path.display()),
+            }
+        }
+
+        let (drone_build_info, response) = self
+            .send_with(
+                request,
+                self.client
+                    .get(self.repo_url(&format!("builds/{build_number}"))),
+                |response| deserialize_build_info(self.instance, request, response),
+            )
+            .await?;
+
+        // builds that haven't finished will still change, so only finished
+        // builds are safe to cache
+        if let (Some(cache_path), true) = (cache_path, drone_build_info.is_finished()) {
+            let written = async {
+                if let Some(parent) = cache_path.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+                fs::write(&cache_path, &response).await
+            };
+            if let Err(error) = written.await {
+                warn!("Failed to cache '{}': {error}", cache_path.display());
+            }
+        }
+        Ok(drone_build_info)
+    }
+}
+
+/// `DroneBuildsPaginator` for `AsyncDroneClient`, turned into a `Stream` of
+/// builds with `into_stream`
+#[derive(Debug, Clone)]
+pub struct AsyncDroneBuildsPaginator<'drone> {
+    drone: &'drone AsyncDroneClient,
+    pages: BuildPages,
+}
+
+impl<'drone> AsyncDroneBuildsPaginator<'drone> {
+    pub fn skip_pages(mut self, pages: usize) -> Self {
+        self.pages.skip_pages(pages);
+        self
+    }
+
+    /// Number of builds requested per page, at most `MAX_PAGE_SIZE`
+    pub fn per_page(mut self, per_page: usize) -> Self {
+        self.pages.per_page(per_page);
+        self
+    }
+
+    /// Ends the stream after it has yielded `max_builds` builds
+    pub fn max_builds(mut self, max_builds: usize) -> Self {
+        self.pages.remaining = Some(max_builds);
+        self
+    }
+
+    pub fn pagination(mut self, pagination: Pagination) -> Self {
+        self.pages.pagination = pagination;
+        self
+    }
+
+    /// Ends the stream at the first build numbered `since_build` or lower
+    pub fn since_build(mut self, since_build: u32) -> Self {
+        self.pages.since_build = Some(since_build);
+        self
+    }
+
+    /// Builds newest first, fetching the next page once the previous one has
+    /// been consumed; the stream ends after the first error
+    pub fn into_stream(
+        self,
+    ) -> impl Stream<Item = Result<DroneBuildListItem, DroneError>> + 'drone {
+        stream::unfold(Some(self), |paginator| async move {
+            let mut paginator = paginator?;
+            if let Some(page) = paginator.pages.next_request() {
+                let builds = paginator
+                    .drone
+                    .get_build_list(page, paginator.pages.per_page)
+                    .await;
+                match builds {
+                    Ok(builds) => paginator.pages.add_page(builds),
+                    Err(error) => return Some((Err(error), None)),
+                }
+            }
+            let drone_build_list_item = paginator.pages.next_build()?;
+            Some((Ok(drone_build_list_item), Some(paginator)))
+        })
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// The stages of a build and the stages each of them depends on
+#[derive(Debug, Clone)]
+pub struct StageGraph {
+    pub build_number: u32,
+    pub stages: Vec<GraphStage>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GraphStage {
+    pub name: String,
+    /// Seconds; 0 for stages that never ran
+    pub elapsed_time: i64,
+    pub depends_on: Vec<String>,
+}
+
+impl StageGraph {
+    /// Chain of dependent stages with the largest combined elapsed time, in
+    /// the order they ran, and that time; `None` when the dependencies
+    /// contain a cycle. Dependencies on stages missing from the build are
+    /// ignored.
+    pub fn critical_path(&self) -> Option<(Vec<&str>, i64)> {
+        let stages: HashMap<&str, &GraphStage> = self
+            .stages
+            .iter()
+            .map(|stage| (stage.name.as_str(), stage))
+            .collect();
+        let mut longest = HashMap::new();
+        let mut visiting = HashSet::new();
+        for stage in &self.stages {
+            longest_path_to(&stage.name, &stages, &mut longest, &mut visiting)?;
+        }
+
+        let (mut name, &(elapsed_time, _)) = longest
+            .iter()
+            .max_by_key(|(name, (elapsed_time, _))| (*elapsed_time, std::cmp::Reverse(**name)))?;
+        let mut path = vec![*name];
+        while let Some((_, Some(previous))) = longest.get(name) {
+            path.push(previous);
+            name = previous;
+        }
+        path.reverse();
+        Some((path, elapsed_time))
+    }
+}
+
+/// Memoizes, per stage, the longest elapsed time of a chain ending in it and
+/// the stage before it in that chain
+fn longest_path_to<'graph>(
+    name: &'graph str,
+    stages: &HashMap<&'graph str, &'graph GraphStage>,
+    longest: &mut HashMap<&'graph str, (i64, Option<&'graph str>)>,
+    visiting: &mut HashSet<&'graph str>,
+) -> Option<i64> {
+    if let Some((elapsed_time, _)) = longest.get(name) {
+        return Some(*elapsed_time);
+    }
+    if !visiting.insert(name) {
+        return None;
+    }
+    let stage = stages[name];
+    let mut before: (i64, Option<&str>) = (0, None);
+    for dependency in &stage.depends_on {
+        if !stages.contains_key(dependency.as_str()) {
+            continue;
+        }
+        let elapsed_time = longest_path_to(dependency, stages, longest, visiting)?;
+        if elapsed_time > before.0 || before.1.is_none() {
+            before = (elapsed_time, Some(dependency));
+        }
+    }
+    visiting.remove(name);
+    let elapsed_time = before.0 + stage.elapsed_time;
+    longest.insert(name, (elapsed_time, before.1));
+    Some(elapsed_time)
+}
+
+/// Graphviz DOT, with the critical path drawn in red
+impl fmt::Display for StageGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let quote = |name: &str| format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""));
+        let critical_path = self
+            .critical_path()
+            .map(|(path, _)| path)
+            .unwrap_or_default();
+        let on_critical_path = |from: &str, to: &str| {
+            critical_path
+                .windows(2)
+                .any(|edge| edge[0] == from && edge[1] == to)
+        };
+
+        writeln!(f, "digraph \"build {}\" {{", self.build_number)?;
+        for stage in &self.stages {
+            writeln!(
+                f,
+                "    {} [label={}];",
+                quote(&stage.name),
+                quote(&format!("{}\n{}s", stage.name, stage.elapsed_time))
+            )?;
+        }
+        for stage in &self.stages {
+            for dependency in &stage.depends_on {
+                let color = if on_critical_path(dependency, &stage.name) {
+                    " [color=red]"
+                } else {
+                    ""
+                };
+                writeln!(
+                    f,
+                    "    {} -> {}{color};",
+                    quote(dependency),
+                    quote(&stage.name)
+                )?;
+            }
+        }
+        write!(f, "}}")
+    }
+}
@@ -0,0 +1,23 @@
+pub mod baseline;
+pub mod credentials;
+pub mod csv;
+pub mod drone;
+pub mod graph;
+pub mod output;
+pub mod prometheus;
+pub mod replay;
+pub mod select;
+pub mod stats;
+
+pub use crate::csv::{
+    build_rows, compact_rows, write_csv, Column, CompactRow, DropCounts, OutputFormat,
+    ReportConfig, ReportWriter, Row, StatusColumn, TimeUnit,
+};
+#[cfg(feature = "async")]
+pub use crate::drone::{AsyncDroneBuildsPaginator, AsyncDroneClient};
+pub use crate::drone::{
+    CommitBuildMap, Correlation, DroneBuildInfo, DroneBuildListItem, DroneClient,
+    DroneClientBuilder, DroneError, DroneInstance, DroneStatus, RateLimiter, RepoSlug,
+    RequestLimit, RetryPolicy,
+};
+pub use crate::select::{drone_build_map, SelectConfig, WindowMode};
@@ -1,179 +1,2184 @@
-use clap::Parser;
-use drone::{DroneBuildInfo, DroneBuildListItem, DroneClient, DroneEvent, DroneStatus};
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use chrono::DateTime;
+use clap::builder::{RangedI64ValueParser, RangedU64ValueParser};
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use cuddly_robot::baseline::{Baseline, BaselineError};
+use cuddly_robot::credentials::{resolve_token, Credentials, CredentialsError};
+use cuddly_robot::csv::{
+    build_rows, compact_rows, step_rows, write_compact_rows, write_step_rows, Column, DropCounts,
+    OutputFormat, PickStrategy, ReportConfig, ReportWriter, Row, StatusColumn, StepLocation,
+    StepPair, TimeUnit,
+};
+use cuddly_robot::drone::{
+    BuildMode, CommitBuildMap, Correlation, DroneBuildInfo, DroneClient, DroneError, DroneInstance,
+    DroneStatus, InvalidRepoSlug, LogLine, Pagination, RateLimiter, RepoSlug, RequestLimit,
+    RetryPolicy, DEFAULT_USER_AGENT, MAX_PAGE_SIZE,
+};
+use cuddly_robot::output::OutputTarget;
+use cuddly_robot::prometheus::{self, PrometheusError};
+use cuddly_robot::replay::{read_replay, DumpError, DumpWriter, ReplayError};
+use cuddly_robot::select::{
+    drone_build_map, drone_build_map_chunks, fetch_build_infos, select_build_list, FilterTrace,
+    SelectConfig, TraceError, WindowMode,
+};
+use cuddly_robot::stats;
+use derive_more::{Display, Error, From};
+use indicatif::ProgressBar;
+use log::{warn, LevelFilter};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitCode};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use url::Url;
 
-mod csv;
-mod drone;
+/// Exit status when the report contains no rows, which usually means the
+/// window or stage/step names are misconfigured
+const EMPTY_REPORT_EXIT_CODE: u8 = 2;
 
-static BITGO_DRONE1_URL: &str = "https://drone.bitgo-dev.com";
-static BITGO_DRONE2_URL: &str = "https://drone2.bitgo-ci.com";
+/// Exit status when a written row has a status given to --fail-on-status
+const FAILED_STATUS_EXIT_CODE: u8 = 3;
+
+/// Lines of a failed await step's log kept by --with-error-tail
+const ERROR_TAIL_LINES: usize = 5;
+
+/// Shortest --commit prefix accepted, as short as `git log --oneline` goes
+const MIN_COMMIT_PREFIX: usize = 7;
+
+/// Builds looked at on each instance for --commit without --max-builds
+const COMMIT_MAX_BUILDS: usize = 1000;
+
+/// Commits whose builds are fetched before their rows are written out
+const COMMITS_PER_CHUNK: usize = 50;
+
+/// Characters each line of an error tail is cut to
+const ERROR_TAIL_LINE_CHARS: usize = 200;
+
+/// Colours and cursor movement in step logs, compiled on first use
+static ANSI_ESCAPE: OnceLock<Regex> = OnceLock::new();
+
+/// Exit status after Ctrl-C, the shell's 128 + SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
     /// Window size in hours within which to compare build metrics;
-    /// builds must both be created and finished within window
-    #[clap(value_parser)]
-    window_duration: u64,
+    /// builds must both be created and finished within window. Required
+    /// unless the window is given by --preset or --from/--to, or no window
+    /// is needed
+    #[clap(value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    window_duration: Option<u64>,
     /// Offset in hours to start metric comparison
     #[clap(short, long, value_parser)]
     window_offset: Option<u64>,
+    /// Window ending at the last full hour and spanning the preset's
+    /// duration; window-duration and --window-offset override its values
+    #[clap(long, value_enum)]
+    preset: Option<WindowPreset>,
+    /// RFC 3339 timestamp at which the window begins, e.g.
+    /// 2022-09-01T09:00:00-04:00; replaces the hour-based window
+    #[clap(long, value_parser = parse_rfc3339, requires = "to", conflicts_with_all = &["window-duration", "window-offset", "preset"])]
+    from: Option<SystemTime>,
+    /// Compare only the builds of this commit, given as its full sha or at
+    /// least its first 7 characters, instead of the builds within a window;
+    /// the most recent 1000 builds of each instance are searched unless
+    /// --max-builds says otherwise
+    #[clap(long, value_parser = parse_commit, conflicts_with_all = &["window-duration", "window-offset", "from", "preset"])]
+    commit: Option<String>,
+    /// Compare the builds in this file, a JSON array or JSON Lines of
+    /// `{"instance": "drone1"|"drone2", "build": {...}}` records, instead of
+    /// fetching any from Drone; every build in it is compared regardless of
+    /// the window
+    #[clap(long, value_parser, value_name = "FILE", conflicts_with_all = &["window-duration", "window-offset", "from", "preset", "commit", "dry-run", "dump-logs", "repos-file"])]
+    replay: Option<PathBuf>,
+    /// Write the selection decision for every build looked at in the build
+    /// lists to this file as JSON Lines: its number, event, status and
+    /// timestamps, and whether it was selected, skipped (with the reason) or
+    /// ended the walk
+    #[clap(long, value_parser, value_name = "FILE", conflicts_with_all = &["replay", "commit"])]
+    trace_out: Option<PathBuf>,
+    /// Write every build fetched from Drone to this file as JSON Lines, in
+    /// the format read by --replay
+    #[clap(long, value_parser, value_name = "FILE", conflicts_with_all = &["replay", "dry-run"])]
+    dump: Option<PathBuf>,
+    /// RFC 3339 timestamp at which the window ends
+    #[clap(long, value_parser = parse_rfc3339, requires = "from")]
+    to: Option<SystemTime>,
+    /// Which builds at the edges of the window are compared: only those
+    /// created and finished within it, or also those created before it
+    #[clap(long, value_enum, default_value_t = WindowMode::Strict)]
+    window_mode: WindowMode,
+    /// File to write the report to instead of stdout; in builds with the s3
+    /// feature, `s3://bucket/key` uploads the report there once complete
     #[clap(short, long, value_parser)]
-    file: Option<PathBuf>,
+    file: Option<OutputTarget>,
+    /// Append to --file instead of overwriting it, e.g. to keep a rolling
+    /// report across hourly runs; the TSV header is only written when the
+    /// file is empty, so every run should write the same columns
+    #[clap(long, value_parser, requires = "file", conflicts_with_all = &["compact", "steps"])]
+    append: bool,
+    /// Only write rows whose Drone1 unit test, Drone1 await and Drone2 system
+    /// statuses are all among these, e.g. `--status success`
+    #[clap(long, value_enum, value_delimiter = ',')]
+    status: Vec<DroneStatus>,
+    /// Only compare builds whose target branch matches this pattern, e.g.
+    /// `^release/.*`; applies on top of --mode
+    #[clap(long, value_parser)]
+    target_branch: Option<Regex>,
+    /// Only compare builds whose source branch matches this pattern
+    #[clap(long, value_parser)]
+    source_branch: Option<Regex>,
+    /// Only compare builds by this author login, matched case-insensitively;
+    /// repeat or separate with commas for several authors
+    #[clap(long, value_parser, value_delimiter = ',')]
+    author: Vec<String>,
+    /// Which build to compare when a commit was built more than once on a
+    /// Drone instance, chosen separately for each instance
+    #[clap(long, value_enum, default_value_t = PickStrategy::First)]
+    pick: PickStrategy,
+    /// Only fetch the details of the highest-numbered build of each commit on
+    /// each Drone instance, skipping the requests for its earlier runs; like
+    /// `--pick last`, but decided from the build lists
+    #[clap(long, value_parser, conflicts_with = "pick")]
+    head_only: bool,
+    /// What Drone1 and Drone2 builds are matched up by: the sha they built,
+    /// or their pull request number for when the instances build different
+    /// shas of a pull request, at the cost of possibly comparing builds of
+    /// different pushes
+    #[clap(long, value_enum, default_value_t = Correlation::Sha)]
+    correlate_by: Correlation,
+    /// Write rows for builds whose unit test step was skipped, with empty
+    /// timings, instead of leaving them out
+    #[clap(long, value_parser)]
+    include_skipped: bool,
+    /// Write every status as success or failure, counting killed and error
+    /// as failure and skipped as success; --status and --fail-on-status
+    /// match the written statuses
+    #[clap(long, value_parser)]
+    normalize_status: bool,
+    /// Exit with status 3 when any written row has one of these statuses in
+    /// a --fail-on-column column, e.g. `--fail-on-status failure,error`;
+    /// the report is still written in full
+    #[clap(long, value_enum, value_delimiter = ',')]
+    fail_on_status: Vec<DroneStatus>,
+    /// Status columns checked by --fail-on-status; all of them by default
+    #[clap(long, value_enum, value_delimiter = ',', requires = "fail-on-status")]
+    fail_on_column: Vec<StatusColumn>,
+    /// Exit successfully even when no builds could be compared
+    #[clap(long, value_parser)]
+    allow_empty: bool,
+    /// Compare builds of repositories that are inactive on either Drone
+    /// instance instead of failing; the repository is checked before its
+    /// builds are walked otherwise
+    #[clap(long, value_parser)]
+    allow_inactive: bool,
+    /// Only walk the build lists and report, per Drone instance, how many
+    /// build details would be fetched; no report is written
+    #[clap(long, value_parser)]
+    dry_run: bool,
+    /// Only compare the build-level status and elapsed time of each commit's
+    /// builds, taken from the build lists, instead of fetching every build's
+    /// details; the stage and step options don't apply
+    #[clap(long, value_parser, conflicts_with_all = &["columns", "time-unit", "compare", "include-skipped", "baseline", "summary", "prometheus-out", "dump", "dump-logs", "fail-on-status"])]
+    compact: bool,
+    /// Print count, mean, median, p90 and p99 of the elapsed times, and how
+    /// often the await threshold was breached, to stderr
+    #[clap(long, value_parser)]
+    summary: bool,
+    /// Write the --summary statistics of each repository to this file in
+    /// the Prometheus text format, e.g. for node-exporter's textfile collector
+    #[clap(long, value_parser)]
+    prometheus_out: Option<PathBuf>,
+    /// Write a summary of the run (rows written, builds fetched per
+    /// instance, dropped comparisons by reason and elapsed time) to stderr
+    /// as its last line, for scripts wrapping the report
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    run_summary: Option<RunSummaryFormat>,
+    /// Fetch and write out the Drone1 logs of every failed unit test or
+    /// await step in the report
+    #[clap(long, value_parser)]
+    dump_logs: bool,
+    /// Directory to write --dump-logs files into instead of stderr
+    #[clap(long, value_parser, requires = "dump-logs")]
+    logs_dir: Option<PathBuf>,
+    /// Add a drone1_await_error_tail column with the last lines of the log
+    /// of every failed Drone1 await step; each one costs a request
+    #[clap(long, value_parser, conflicts_with_all = &["compact", "replay"])]
+    with_error_tail: bool,
+    /// Log more detail to stderr: -v for progress, -vv for skipped builds;
+    /// RUST_LOG takes precedence when set
+    #[clap(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Don't show a progress spinner while fetching builds
     #[clap(short, long, value_parser)]
+    quiet: bool,
+    /// Format of the comparison report
+    #[clap(long, value_enum, default_value_t = OutputFormat::Tsv)]
+    format: OutputFormat,
+    /// Field delimiter of the tsv format: a single character, `tab` or `comma`
+    #[clap(long, value_parser = parse_delimiter, default_value = "tab")]
+    delimiter: u8,
+    /// Only write these columns, in this order, e.g.
+    /// `--columns pr_number,git_sha,drone1_unit_test_elapsed_time`;
+    /// `drone1_trigger` and `drone2_sender` are only written when listed here
+    #[clap(long, value_enum, value_delimiter = ',')]
+    columns: Vec<Column>,
+    /// Unit of the drone1_unit_test_elapsed_time and
+    /// drone2_total_elapsed_time columns; millis only multiplies, as Drone
+    /// timestamps are whole seconds
+    #[clap(long, value_enum, default_value_t = TimeUnit::Seconds)]
+    time_unit: TimeUnit,
+    /// Earlier tsv report, written with every column and --time-unit
+    /// seconds, to compare elapsed times with; adds the change of each
+    /// row's elapsed times versus the baseline row with the same repo, PR
+    /// number, git sha and compared pair
+    #[clap(long, value_parser, value_name = "FILE")]
+    baseline: Option<PathBuf>,
+    /// Kind of builds to compare
+    #[clap(long, value_enum, default_value_t = BuildMode::PullRequest)]
+    mode: BuildMode,
+    /// Deprecated alias of `--mode develop`
+    #[clap(short, long, value_parser, conflicts_with = "mode", hide = true)]
     develop: bool,
-    #[clap(env = "DRONE1_TOKEN")]
-    drone1_token: String,
-    #[clap(env = "DRONE2_TOKEN")]
-    drone2_token: String,
+    /// Seconds after the unit test step starts within which the await step
+    /// must finish to count as 'await_within_three_minutes_of_unit_test_start';
+    /// despite the column's name, five minutes by default, as it always was
+    #[clap(long, value_parser = RangedI64ValueParser::<i64>::new().range(1..), default_value_t = 300)]
+    await_threshold_secs: i64,
+    /// Hold --await-threshold-secs against how far apart the two are either
+    /// way, so an await step finishing long before the unit test step
+    /// started isn't counted as within it; the delta column stays signed
+    #[clap(long, value_parser)]
+    await_abs: bool,
+    /// Seconds the Drone2 build may seem to start after the Drone1 await
+    /// step finished, or before the Drone1 build was created, which only
+    /// clock skew between the instances explains, before
+    /// 'clock_skew_suspected' flags the row
+    #[clap(long, value_parser = RangedI64ValueParser::<i64>::new().range(0..), default_value_t = 60)]
+    skew_tolerance_secs: i64,
+    /// Drone1 stage containing the unit test and await steps for pull requests
+    #[clap(long, value_parser, default_value = "build-pull-request")]
+    pr_stage: String,
+    /// Drone1 stage containing the unit test and await steps with
+    /// `--mode develop`
+    #[clap(long, value_parser, default_value = "build-develop")]
+    develop_stage: String,
+    /// Drone1 stage containing the unit test and await steps with `--mode tag`
+    #[clap(long, value_parser, default_value = "build-tag")]
+    tag_stage: String,
+    /// Drone1 step running the unit tests
+    #[clap(long, value_parser, default_value = "run-wallet-platform-unit-tests")]
+    unit_test_step: String,
+    /// Drone1 step awaiting the Drone2 system test status
+    #[clap(
+        long,
+        value_parser,
+        default_value = "await-wallet-platform-test-status"
+    )]
+    await_step: String,
+    /// Drone1 `stage:step` pair to compare instead of the stage and unit test
+    /// step above; repeat to emit one row per pair. Pairs whose stage or step
+    /// is missing from a build are logged as warnings and produce no row
+    #[clap(long, value_parser)]
+    compare: Vec<StepPair>,
+    /// Pattern matching the Drone2 stages that make up the system tests
+    #[clap(long, value_parser, default_value = "^wallet-platform-.*")]
+    system_stage_regex: Regex,
+    /// Only consider stages of this pipeline type, e.g. 'docker' or
+    /// 'kubernetes', for the system status and the compared steps; stages
+    /// without a type, like those of Drone1 deployments that don't send one,
+    /// are always considered
+    #[clap(long, value_parser)]
+    stage_type: Option<String>,
+    /// Number of times a failed Drone request is retried on connection
+    /// errors, 429 and 5xx responses, with exponential backoff or as long as
+    /// a 429's Retry-After asks
+    #[clap(long, value_parser, default_value_t = 3)]
+    max_retries: u32,
+    /// Combined requests per second sent to both Drone instances, e.g. 0.5
+    /// for one request every two seconds; unlimited by default
+    #[clap(long, value_parser = parse_rate_limit, value_name = "RPS")]
+    rate_limit: Option<f64>,
+    /// Stop walking each Drone instance's build list after this many builds,
+    /// regardless of the window
+    #[clap(long, value_parser)]
+    max_builds: Option<usize>,
+    /// Stop walking each Drone instance's build list at the first build
+    /// numbered this or lower, e.g. the last build of a previous run; build
+    /// numbers are counted separately by each instance
+    #[clap(long, value_parser)]
+    since_build: Option<u32>,
+    /// Only compare builds numbered within this inclusive range, e.g.
+    /// `4560..4600`, stopping at the first build below it; no window is
+    /// needed along with it, as any window given still applies
+    #[clap(long, value_parser = parse_build_range, value_name = "N..M")]
+    build_range: Option<RangeInclusive<u32>>,
+    /// Number of builds requested per build list page
+    #[clap(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..=MAX_PAGE_SIZE as u64), default_value_t = MAX_PAGE_SIZE)]
+    page_size: usize,
+    /// How build lists are paged through; `cursor` avoids duplicates and
+    /// gaps when builds are created during the walk
+    #[clap(long, value_enum, default_value_t = Pagination::Page)]
+    pagination: Pagination,
+    /// Maximum number of requests in flight to each Drone instance at the
+    /// same time, and so of build details fetched at once. --rate-limit
+    /// spaces the requests out on top of it, a request waiting on the rate
+    /// limit counting as in flight
+    #[clap(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..), default_value_t = 8)]
+    concurrency: usize,
+    /// Milliseconds over which the --concurrency requests fetching build
+    /// details start, each after a random delay, rather than all at once.
+    /// --rate-limit still applies on top of the ramp, so with a rate limit
+    /// low enough to hold the requests back there's little left to smooth
+    #[clap(long, value_parser, default_value_t = 0)]
+    ramp_ms: u64,
+    /// Proxy to send Drone requests through; HTTPS_PROXY and HTTP_PROXY are
+    /// used when not given
+    #[clap(long, value_parser)]
+    proxy: Option<Url>,
+    /// Connect to Drone directly, ignoring HTTPS_PROXY and HTTP_PROXY
+    #[clap(long, value_parser, conflicts_with = "proxy")]
+    no_proxy: bool,
+    /// PEM certificate of an extra root CA to trust, for Drone instances whose
+    /// certificate isn't signed by a CA in the system trust store
+    #[clap(long, value_parser)]
+    ca_cert: Option<PathBuf>,
+    /// Don't verify Drone's TLS certificate at all; for development only
+    #[clap(long, value_parser)]
+    insecure_skip_tls_verify: bool,
+    /// Ask Drone for uncompressed responses, e.g. when debugging through a
+    /// proxy that mangles gzip or deflate encoded bodies
+    #[clap(long, value_parser)]
+    no_compression: bool,
+    /// User-Agent sent with every Drone request
+    #[clap(long, value_parser, default_value = DEFAULT_USER_AGENT)]
+    user_agent: String,
+    /// Cache the details of finished builds in this directory and reuse them
+    /// on later runs
+    #[clap(long, value_parser)]
+    cache_dir: Option<PathBuf>,
+    /// Seconds allowed for each Drone request before it is abandoned
+    #[clap(long, value_parser, default_value_t = 30)]
+    timeout_secs: u64,
+    /// Seconds allowed for establishing a connection to Drone
+    #[clap(long, value_parser, default_value_t = 10)]
+    connect_timeout_secs: u64,
+    /// Most idle connections kept open to each Drone instance for reuse;
+    /// --concurrency by default, so each concurrent build detail request
+    /// finds a connection to reuse instead of opening a new one
+    #[clap(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pool_max_idle_per_host: Option<usize>,
+    /// Seconds an idle connection to Drone is kept open for reuse
+    #[clap(long, value_parser, default_value_t = 90)]
+    pool_idle_timeout_secs: u64,
+    /// Pair of Drone instances to compare, providing the default
+    /// --drone1-url and --drone2-url and the variables the tokens are read
+    /// from: the built-in `bitgo`, or one defined in the --config file as
+    /// `[envs.<name>]` with `drone1_url`, `drone2_url` and, optionally,
+    /// `drone1_token_var` and `drone2_token_var`, DRONE1_TOKEN and
+    /// DRONE2_TOKEN when not given
+    #[clap(long, value_parser, default_value = BITGO_ENV)]
+    env: String,
+    /// The instances --env names, looked up by `parse_cli`
+    #[clap(skip)]
+    drone_env: DroneEnv,
+    /// Base URL of the Drone1 instance; the --env's by default
+    #[clap(long, env = "DRONE1_URL", value_parser)]
+    drone1_url: Option<Url>,
+    /// Base URL of the Drone2 instance; the --env's by default
+    #[clap(long, env = "DRONE2_URL", value_parser)]
+    drone2_url: Option<Url>,
+    /// Repository to compare builds for, as 'owner/name'
+    #[clap(long, value_parser, default_value = "BitGo/bitgo-microservices")]
+    repo: RepoSlug,
+    /// File listing one 'owner/name' repository per line to compare builds
+    /// for in turn, instead of --repo; rows are prefixed with their
+    /// repository and a failing repository doesn't stop the others
+    #[clap(long, value_parser, conflicts_with = "repo")]
+    repos_file: Option<PathBuf>,
+    /// Restart this build instead of producing a report; the token needs
+    /// write access to the repository
+    #[clap(long, value_parser, value_name = "BUILD_NUMBER")]
+    restart: Option<u32>,
+    /// Print the stage dependency graph of this Drone2 build as Graphviz DOT
+    /// instead of producing a report, with its critical path in red
+    #[clap(
+        long,
+        value_parser,
+        value_name = "BUILD_NUMBER",
+        conflicts_with = "restart"
+    )]
+    graph: Option<u32>,
+    /// Write the status and timings of every step of this build instead of
+    /// producing a report, in --format to --file or stdout
+    #[clap(long, value_parser, value_name = "BUILD_NUMBER", conflicts_with_all = &["restart", "graph"])]
+    steps: Option<u32>,
+    /// List the repositories the tokens can access on each Drone instance,
+    /// one `instance<TAB>owner/name` line per repository, instead of
+    /// producing a report; inactive repositories are marked as such
+    #[clap(long, value_parser, conflicts_with_all = &["restart", "graph", "steps"])]
+    list_repos: bool,
+    /// Print how many builds --repo has on each Drone instance, one
+    /// `instance<TAB>count` line per instance, instead of producing a
+    /// report; the count is `unknown` when Drone doesn't report it
+    #[clap(long, value_parser, conflicts_with_all = &["restart", "graph", "steps", "list-repos"])]
+    count: bool,
+    /// Keep polling Drone every --interval-secs and write the rows of new
+    /// builds as they finish, until interrupted; the window keeps its
+    /// duration but ends at the time of each poll, and each commit is
+    /// reported once, with the builds finished by then
+    #[clap(long, value_parser, conflicts_with_all = &["restart", "graph", "steps", "list-repos", "count", "repos-file", "commit", "replay", "from", "preset", "dry-run", "compact", "baseline", "summary", "prometheus-out", "dump", "fail-on-status"])]
+    follow: bool,
+    /// Seconds to wait between --follow polls
+    #[clap(long, value_parser = RangedU64ValueParser::<u64>::new().range(1..), default_value_t = 60)]
+    interval_secs: u64,
+    /// Drone instance the --steps build belongs to
+    #[clap(long, value_enum, default_value_t = DroneInstance::Drone1)]
+    steps_instance: DroneInstance,
+    /// Drone instance the --restart build belongs to
+    #[clap(long, value_enum, default_value_t = DroneInstance::Drone1)]
+    restart_instance: DroneInstance,
+    /// JSON or TOML file with 'drone1_token' and 'drone2_token' keys; tokens
+    /// given on the command line or environment take precedence
+    #[clap(long, value_parser)]
+    credentials_file: Option<PathBuf>,
+    /// Fail instead of warning when both instances are given the same token
+    /// although their URLs differ
+    #[clap(long, value_parser)]
+    strict_tokens: bool,
+    /// TOML file of option values keyed by their long name, with
+    /// underscores, e.g. `drone1_url = "https://drone.example.com"` or
+    /// `window_duration = 5`, and of `[envs.<name>]` tables defining more
+    /// environments for --env; the command line and environment take precedence over it
+    #[clap(long, value_parser, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Token of the Drone1 instance; read from the --env's variable,
+    /// DRONE1_TOKEN by default, when not given
+    #[clap(value_parser)]
+    drone1_token: Option<String>,
+    /// Token of the Drone2 instance; read from the --env's variable,
+    /// DRONE2_TOKEN by default, when not given. Without a Drone2 token,
+    /// Drone1 builds are reported on their own
+    #[clap(value_parser)]
+    drone2_token: Option<String>,
 }
 
-fn timestamp_to_system_time(timestamp: i64) -> SystemTime {
-    UNIX_EPOCH + Duration::from_secs(timestamp.unsigned_abs())
+/// The --env built in, BitGo's Drone1 at drone.bitgo-dev.com and Drone2 at
+/// drone2.bitgo-ci.com, with DRONE1_TOKEN and DRONE2_TOKEN
+const BITGO_ENV: &str = "bitgo";
+
+/// A pair of Drone instances, along with the environment variables holding
+/// their tokens, as defined by an `[envs.<name>]` table of the --config file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DroneEnv {
+    drone1_url: Url,
+    drone2_url: Url,
+    #[serde(default = "default_drone1_token_var")]
+    drone1_token_var: String,
+    #[serde(default = "default_drone2_token_var")]
+    drone2_token_var: String,
 }
 
-fn get_window_bounds(cli: &Cli) -> (SystemTime, SystemTime) {
-    let window_start = if let Some(window_offset) = cli.window_offset {
-        SystemTime::now() - Duration::from_secs(window_offset * 60 * 60)
-    } else {
-        SystemTime::now()
+fn default_drone1_token_var() -> String {
+    "DRONE1_TOKEN".to_string()
+}
+
+fn default_drone2_token_var() -> String {
+    "DRONE2_TOKEN".to_string()
+}
+
+/// `BITGO_ENV`
+impl Default for DroneEnv {
+    fn default() -> Self {
+        DroneEnv {
+            drone1_url: Url::parse("https://drone.bitgo-dev.com").expect("the URL is valid"),
+            drone2_url: Url::parse("https://drone2.bitgo-ci.com").expect("the URL is valid"),
+            drone1_token_var: default_drone1_token_var(),
+            drone2_token_var: default_drone2_token_var(),
+        }
+    }
+}
+
+impl DroneEnv {
+    fn url(&self, instance: DroneInstance) -> Url {
+        match instance {
+            DroneInstance::Drone1 => self.drone1_url.clone(),
+            DroneInstance::Drone2 => self.drone2_url.clone(),
+        }
+    }
+
+    /// Environment variable holding the token of `instance`
+    fn token_var(&self, instance: DroneInstance) -> &str {
+        match instance {
+            DroneInstance::Drone1 => &self.drone1_token_var,
+            DroneInstance::Drone2 => &self.drone2_token_var,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum RunSummaryFormat {
+    /// One JSON object
+    Json,
+}
+
+/// What a run did, for --run-summary
+#[derive(Debug, Default, Serialize)]
+struct RunSummary {
+    rows_written: usize,
+    /// Builds fetched from, or replayed for, each instance
+    drone1_builds_fetched: usize,
+    drone2_builds_fetched: usize,
+    dropped: DropCounts,
+    elapsed_secs: f64,
+}
+
+impl RunSummary {
+    fn record_builds(&mut self, commit_build_map: &CommitBuildMap) {
+        for (drone1_builds, drone2_builds) in commit_build_map.values() {
+            self.drone1_builds_fetched += drone1_builds.len();
+            self.drone2_builds_fetched += drone2_builds.len();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum WindowPreset {
+    /// The last full hour
+    #[clap(name = "last-hour")]
+    Hour,
+    /// The 24 hours up to the last full hour
+    #[clap(name = "last-day")]
+    Day,
+    /// The 7 days up to the last full hour
+    #[clap(name = "last-week")]
+    Week,
+}
+
+impl WindowPreset {
+    fn hours(self) -> u64 {
+        match self {
+            WindowPreset::Hour => 1,
+            WindowPreset::Day => 24,
+            WindowPreset::Week => 7 * 24,
+        }
+    }
+}
+
+#[derive(Debug, Display, Error, From)]
+enum RunError {
+    Drone(DroneError),
+    Credentials(CredentialsError),
+    Window(WindowError),
+    Replay(ReplayError),
+    Dump(DumpError),
+    Baseline(BaselineError),
+    Prometheus(PrometheusError),
+    Trace(TraceError),
+    #[display(fmt = "failed to install the Ctrl-C handler: {}", _0)]
+    CtrlC(ctrlc::Error),
+    #[display(fmt = "failed to write the report: {}", _0)]
+    Output(io::Error),
+    #[display(
+        fmt = "repository '{}' is inactive on {}; pass --allow-inactive to compare it anyway",
+        repo,
+        instance
+    )]
+    #[from(ignore)]
+    InactiveRepo {
+        #[error(not(source))]
+        repo: RepoSlug,
+        instance: DroneInstance,
+    },
+    #[display(fmt = "drone1 and drone2 were given the same token although their URLs differ")]
+    #[from(ignore)]
+    SameToken,
+    #[display(fmt = "--append only appends to local files")]
+    #[from(ignore)]
+    AppendToObject,
+    #[display(
+        fmt = "no {} builds found for commit '{}' among its most recent builds; raise --max-builds to search further",
+        instance,
+        sha
+    )]
+    #[from(ignore)]
+    NoBuildsForCommit {
+        #[error(not(source))]
+        sha: String,
+        instance: DroneInstance,
+    },
+    #[display(
+        fmt = "commit '{}' is ambiguous on {}, it could be any of {}",
+        sha,
+        instance,
+        "matches.join(\", \")"
+    )]
+    #[from(ignore)]
+    AmbiguousCommit {
+        #[error(not(source))]
+        sha: String,
+        instance: DroneInstance,
+        matches: Vec<String>,
+    },
+    #[display(fmt = "failed to read repos file '{}': {}", "path.display()", source)]
+    #[from(ignore)]
+    ReadReposFile {
+        path: PathBuf,
+        source: io::Error,
+    },
+    #[display(fmt = "{}:{}: {}", "path.display()", line_number, source)]
+    #[from(ignore)]
+    InvalidRepo {
+        path: PathBuf,
+        line_number: usize,
+        source: InvalidRepoSlug,
+    },
+}
+
+#[derive(Debug, Display, Error)]
+enum ConfigError {
+    #[display(fmt = "failed to read config file '{}': {}", "path.display()", source)]
+    Read { path: PathBuf, source: io::Error },
+    #[display(fmt = "failed to parse config file '{}': {}", "path.display()", source)]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[display(
+        fmt = "config file '{}' has unknown option '{}'",
+        "path.display()",
+        key
+    )]
+    UnknownOption {
+        path: PathBuf,
+        #[error(not(source))]
+        key: String,
+    },
+    #[display(
+        fmt = "config file '{}' option '{}' must be a string, number, boolean or array of them",
+        "path.display()",
+        key
+    )]
+    InvalidType {
+        path: PathBuf,
+        #[error(not(source))]
+        key: String,
+    },
+    #[display(
+        fmt = "config file '{}' option '{}': {}",
+        "path.display()",
+        key,
+        message
+    )]
+    InvalidValue {
+        path: PathBuf,
+        key: String,
+        #[error(not(source))]
+        message: String,
+    },
+    #[display(
+        fmt = "config file '{}' has invalid envs: {}",
+        "path.display()",
+        source
+    )]
+    InvalidEnvs {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[display(
+        fmt = "unknown --env '{}'; only {} is built in, others are defined as [envs.{}] in the --config file",
+        env,
+        BITGO_ENV,
+        env
+    )]
+    UnknownEnv {
+        #[error(not(source))]
+        env: String,
+    },
+}
+
+#[derive(Debug, Display, Error)]
+enum WindowError {
+    #[display(
+        fmt = "no window given; pass a window duration, --preset, --from and --to or --build-range, on the command line or in --config"
+    )]
+    MissingDuration,
+    #[display(fmt = "--from must be earlier than --to")]
+    FromNotBeforeTo,
+    #[display(
+        fmt = "window offset of {} hours reaches too far into the past",
+        window_offset
+    )]
+    OffsetTooLarge {
+        #[error(not(source))]
+        window_offset: u64,
+    },
+    #[display(
+        fmt = "window duration of {} hours reaches too far into the past",
+        window_duration
+    )]
+    DurationTooLarge {
+        #[error(not(source))]
+        window_duration: u64,
+    },
+}
+
+impl Cli {
+    /// `--mode`, honouring the deprecated `--develop` flag
+    fn mode(&self) -> BuildMode {
+        if self.develop {
+            BuildMode::Develop
+        } else {
+            self.mode
+        }
+    }
+}
+
+fn parse_rfc3339(timestamp: &str) -> Result<SystemTime, chrono::ParseError> {
+    DateTime::parse_from_rfc3339(timestamp).map(SystemTime::from)
+}
+
+fn parse_delimiter(delimiter: &str) -> Result<u8, String> {
+    match delimiter {
+        "tab" => Ok(b'\t'),
+        "comma" => Ok(b','),
+        _ => match delimiter.as_bytes() {
+            [delimiter] => Ok(*delimiter),
+            _ => Err(format!(
+                "expected a single-byte character, 'tab' or 'comma', got '{delimiter}'"
+            )),
+        },
+    }
+}
+
+fn parse_build_range(build_range: &str) -> Result<RangeInclusive<u32>, String> {
+    let invalid =
+        || format!("expected a range of build numbers like '4560..4600', got '{build_range}'");
+    let (start, end) = build_range.split_once("..").ok_or_else(invalid)?;
+    let start: u32 = start.trim().parse().map_err(|_| invalid())?;
+    let end: u32 = end.trim().parse().map_err(|_| invalid())?;
+    if start > end {
+        return Err(format!(
+            "the range '{build_range}' is empty; its start is above its end"
+        ));
+    }
+    Ok(start..=end)
+}
+
+fn parse_commit(commit: &str) -> Result<String, String> {
+    if !commit.chars().all(|char| char.is_ascii_hexdigit()) {
+        return Err(format!("expected a commit sha, got '{commit}'"));
+    }
+    if commit.len() < MIN_COMMIT_PREFIX || commit.len() > 40 {
+        return Err(format!(
+            "expected a commit sha of {MIN_COMMIT_PREFIX} to 40 characters, got '{commit}'"
+        ));
+    }
+    Ok(commit.to_lowercase())
+}
+
+fn parse_rate_limit(rate_limit: &str) -> Result<f64, String> {
+    match rate_limit.parse::<f64>() {
+        Ok(rate_limit) if rate_limit.is_finite() && rate_limit > 0.0 => Ok(rate_limit),
+        _ => Err(format!(
+            "expected a positive number of requests per second, got '{rate_limit}'"
+        )),
+    }
+}
+
+fn get_window_bounds(cli: &Cli) -> Result<(SystemTime, SystemTime), WindowError> {
+    if let (Some(from), Some(to)) = (cli.from, cli.to) {
+        if from >= to {
+            return Err(WindowError::FromNotBeforeTo);
+        }
+        return Ok((to, from));
+    }
+    // presets count back from the last full hour so repeated runs line up
+    let now = match cli.preset {
+        Some(_) => last_full_hour(SystemTime::now()),
+        None => SystemTime::now(),
+    };
+    let window_start = match cli.window_offset {
+        Some(window_offset) => {
+            hours_before(now, window_offset).ok_or(WindowError::OffsetTooLarge { window_offset })?
+        }
+        None => now,
     };
-    let window_end = window_start - Duration::from_secs(cli.window_duration * 60 * 60);
-    (window_start, window_end)
+    let window_duration = match cli.window_duration.or(cli.preset.map(WindowPreset::hours)) {
+        Some(window_duration) => window_duration,
+        // a build range picks the builds on its own, so the window reaches
+        // back to the first build
+        None if cli.build_range.is_some() => return Ok((window_start, UNIX_EPOCH)),
+        None => return Err(WindowError::MissingDuration),
+    };
+    let window_end = hours_before(window_start, window_duration)
+        .ok_or(WindowError::DurationTooLarge { window_duration })?;
+    Ok((window_start, window_end))
+}
+
+fn last_full_hour(time: SystemTime) -> SystemTime {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs());
+    UNIX_EPOCH + Duration::from_secs(seconds - seconds % (60 * 60))
 }
-enum FilterState {
-    Break,
-    Continue,
-    DroneBuildInfo(DroneBuildInfo),
+
+/// `None` when the hours don't fit in a `Duration` or the result would
+/// precede what `SystemTime` can represent
+fn hours_before(time: SystemTime, hours: u64) -> Option<SystemTime> {
+    let seconds = hours.checked_mul(60 * 60)?;
+    time.checked_sub(Duration::from_secs(seconds))
 }
 
-fn filter_build(
-    drone_build_list_item: &DroneBuildListItem,
-    window_start: &SystemTime,
-    window_end: &SystemTime,
+/// Fetches the details of the builds of commit `sha` on one Drone instance,
+/// or with `compact` keeps them as listed
+fn commit_builds(
     drone_client: &DroneClient,
-    develop: bool,
-) -> FilterState {
-    // if build was created and finished outside window, unlikely any older builds will be within window, ignore and break
-    if timestamp_to_system_time(drone_build_list_item.timestamps.finished) < *window_end
-        && timestamp_to_system_time(drone_build_list_item.timestamps.created) < *window_end
-    {
-        return FilterState::Break;
-    }
-    // if build was created before window_end or finished after window_start, ignore
-    if timestamp_to_system_time(drone_build_list_item.timestamps.finished) > *window_start
-        || timestamp_to_system_time(drone_build_list_item.timestamps.created) < *window_end
-    {
-        return FilterState::Continue;
-    }
-
-    if develop {
-        if !(drone_build_list_item.event == DroneEvent::Push
-            && drone_build_list_item.source == "develop"
-            && drone_build_list_item.target == "develop")
+    instance: DroneInstance,
+    sha: &str,
+    max_builds: usize,
+    concurrency: usize,
+    compact: bool,
+) -> Result<Vec<DroneBuildInfo>, RunError> {
+    let drone_build_list_items = drone_client.get_builds_for_commit(sha, max_builds)?;
+    if drone_build_list_items.is_empty() {
+        return Err(RunError::NoBuildsForCommit {
+            sha: sha.to_string(),
+            instance,
+        });
+    }
+    let mut matches: Vec<String> = drone_build_list_items
+        .iter()
+        .map(|drone_build_list_item| drone_build_list_item.git_metadata.git_sha.clone())
+        .collect();
+    matches.sort();
+    matches.dedup();
+    if matches.len() > 1 {
+        return Err(RunError::AmbiguousCommit {
+            sha: sha.to_string(),
+            instance,
+            matches,
+        });
+    }
+    if compact {
+        return Ok(drone_build_list_items
+            .into_iter()
+            .map(DroneBuildInfo::from)
+            .collect());
+    }
+    let build_numbers: Vec<u32> = drone_build_list_items
+        .iter()
+        .map(|drone_build_list_item| drone_build_list_item.number)
+        .collect();
+    let drone_build_infos = fetch_build_infos(
+        drone_client,
+        &build_numbers,
+        concurrency,
+        &ProgressBar::hidden(),
+    )?;
+    // every build may have been skipped for its shape
+    if drone_build_infos.is_empty() {
+        return Err(RunError::NoBuildsForCommit {
+            sha: sha.to_string(),
+            instance,
+        });
+    }
+    Ok(drone_build_infos)
+}
+
+/// Fills in the drone1_await_error_tail column of `rows`, fetching the log of
+/// every failed await step
+fn add_await_error_tails(drone_client: &DroneClient, rows: &mut [Row]) {
+    for row in rows {
+        row.drone1_await_error_tail = Some(None);
+        if row.drone1_await_test_status != DroneStatus::Failure {
+            continue;
+        }
+        let StepLocation {
+            build_number,
+            stage_number,
+            step_number,
+            step_name,
+        } = &row.drone1_await_test_step;
+        match drone_client.get_step_logs(*build_number, *stage_number, *step_number) {
+            Ok(Some(log_lines)) => {
+                row.drone1_await_error_tail = Some(Some(error_tail(&log_lines)));
+            }
+            Ok(None) => {
+                warn!(
+                    "Logs of step '{step_name}' in drone1 build '{build_number}' have been purged"
+                )
+            }
+            Err(error) => warn!("{error}"),
+        }
+    }
+}
+
+/// The last `ERROR_TAIL_LINES` non-blank lines of a log, joined by ` | ` so
+/// the tail fits in one tsv field; tabs become spaces, colour codes and other
+/// control characters are stripped and each line is cut to
+/// `ERROR_TAIL_LINE_CHARS`
+fn error_tail(log_lines: &[LogLine]) -> String {
+    let ansi_escape = ANSI_ESCAPE
+        .get_or_init(|| Regex::new("\x1b\\[[0-9;?]*[A-Za-z]").expect("ANSI escape regex is valid"));
+    let lines: Vec<String> = log_lines
+        .iter()
+        .flat_map(|line| line.out.lines())
+        .map(|line| {
+            ansi_escape
+                .replace_all(line, "")
+                .chars()
+                .filter_map(|char| match char {
+                    '\t' => Some(' '),
+                    char if char.is_control() => None,
+                    char => Some(char),
+                })
+                .take(ERROR_TAIL_LINE_CHARS)
+                .collect::<String>()
+        })
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    lines[lines.len().saturating_sub(ERROR_TAIL_LINES)..].join(" | ")
+}
+
+/// Writes the logs of every failed step in `rows` to `logs_dir`, one file per
+/// step, or to stderr when no directory is given. Failing to fetch or write a
+/// log is reported but doesn't abort the run.
+fn dump_failed_step_logs(drone_client: &DroneClient, rows: &[Row], logs_dir: Option<&Path>) {
+    let failed_steps = rows.iter().flat_map(|row| {
+        [
+            (row.drone1_unit_test_status, &row.drone1_unit_test_step),
+            (row.drone1_await_test_status, &row.drone1_await_test_step),
+        ]
+        .into_iter()
+        .filter(|(status, _)| *status == DroneStatus::Failure)
+        .map(|(_, step)| step)
+    });
+    for step in failed_steps {
+        let StepLocation {
+            build_number,
+            stage_number,
+            step_number,
+            step_name,
+        } = step;
+        let log_lines = match drone_client.get_step_logs(*build_number, *stage_number, *step_number)
         {
-            return FilterState::Continue;
+            Ok(Some(log_lines)) => log_lines,
+            Ok(None) => {
+                warn!(
+                    "Logs of step '{step_name}' in drone1 build '{build_number}' have been purged"
+                );
+                continue;
+            }
+            Err(error) => {
+                warn!("{error}");
+                continue;
+            }
+        };
+        let written = match logs_dir {
+            Some(logs_dir) => {
+                let path = logs_dir.join(format!("{build_number}-{step_name}.log"));
+                fs::write(
+                    &path,
+                    log_lines
+                        .iter()
+                        .map(|line| line.out.as_str())
+                        .collect::<String>(),
+                )
+            }
+            None => {
+                let mut stderr = io::stderr().lock();
+                writeln!(
+                    stderr,
+                    "==> drone1 build {build_number} step '{step_name}' <=="
+                )
+                .and_then(|()| {
+                    log_lines
+                        .iter()
+                        .try_for_each(|line| stderr.write_all(line.out.as_bytes()))
+                })
+            }
+        };
+        if let Err(error) = written {
+            warn!("Failed to write logs of step '{step_name}' in drone1 build '{build_number}': {error}");
         }
-    } else {
-        if drone_build_list_item.event != DroneEvent::PullRequest {
-            return FilterState::Continue;
+    }
+}
+
+/// The value of `--config` on the command line, if any
+fn config_path() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
         }
     }
-    if drone_build_list_item.status == DroneStatus::Running
-        || drone_build_list_item.status == DroneStatus::Killed
-    {
-        return FilterState::Continue;
+    None
+}
+
+/// Parses the command line with the values of the `--config` file as the
+/// defaults, so that anything given on the command line or in the
+/// environment still takes precedence over the file
+fn parse_cli() -> Result<Cli, ConfigError> {
+    let mut command = Cli::command();
+    let mut envs = BTreeMap::new();
+    if let Some(path) = config_path() {
+        let contents = fs::read_to_string(&path).map_err(|source| ConfigError::Read {
+            path: path.clone(),
+            source,
+        })?;
+        let config: toml::value::Table =
+            toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+                path: path.clone(),
+                source,
+            })?;
+        for (key, value) in config {
+            if key == "envs" {
+                envs = config_envs(&path, value)?;
+                continue;
+            }
+            let id = key.replace('_', "-");
+            let Some(id) = command
+                .get_arguments()
+                .map(|arg| arg.get_id())
+                .find(|arg_id| *arg_id == id && *arg_id != "config")
+            else {
+                return Err(ConfigError::UnknownOption { path, key });
+            };
+            let values = match value {
+                toml::Value::Array(values) => values,
+                value => vec![value],
+            };
+            let values = values
+                .into_iter()
+                .map(|value| match value {
+                    toml::Value::String(value) => Some(value),
+                    toml::Value::Integer(value) => Some(value.to_string()),
+                    toml::Value::Float(value) => Some(value.to_string()),
+                    toml::Value::Boolean(value) => Some(value.to_string()),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| ConfigError::InvalidType {
+                    path: path.clone(),
+                    key: key.clone(),
+                })?;
+            // clap only checks defaults once they're used, or panics on
+            // invalid ones in debug builds, so they're run through the
+            // option's parser on their own first
+            let value_parser = command
+                .get_arguments()
+                .find(|arg| arg.get_id() == id)
+                .expect("the argument was just found")
+                .get_value_parser()
+                .clone();
+            clap::Command::new("config")
+                .no_binary_name(true)
+                .arg(
+                    clap::Arg::new("value")
+                        .value_parser(value_parser)
+                        .multiple_values(true)
+                        .allow_hyphen_values(true),
+                )
+                .try_get_matches_from(&values)
+                .map_err(|error| ConfigError::InvalidValue {
+                    path: path.clone(),
+                    key: key.clone(),
+                    // only the first line explains the problem, the rest
+                    // is usage
+                    message: error
+                        .to_string()
+                        .lines()
+                        .next()
+                        .unwrap_or_default()
+                        .trim_start_matches("error: ")
+                        .to_string(),
+                })?;
+            // clap only takes defaults that live as long as the command,
+            // which is the whole run anyway
+            let values: Vec<&'static str> = values
+                .into_iter()
+                .map(|value| &*String::leak(value))
+                .collect();
+            command = command.mut_arg(id, |arg| {
+                arg.default_values(Vec::leak(values)).required(false)
+            });
+        }
     }
+    let mut cli =
+        Cli::from_arg_matches(&command.get_matches()).unwrap_or_else(|error| error.exit());
+    cli.drone_env = drone_env(&cli.env, envs)?;
+    Ok(cli)
+}
 
-    FilterState::DroneBuildInfo(drone_client.get_build_info(drone_build_list_item.number))
+/// The `[envs.<name>]` tables of the --config file at `path`
+fn config_envs(path: &Path, envs: toml::Value) -> Result<BTreeMap<String, DroneEnv>, ConfigError> {
+    envs.try_into().map_err(|source| ConfigError::InvalidEnvs {
+        path: path.to_path_buf(),
+        source,
+    })
 }
 
-fn drone_build_map(
-    window_start: SystemTime,
-    window_end: SystemTime,
-    drone1_client: DroneClient,
-    drone2_client: DroneClient,
-    develop: bool,
-) -> HashMap<String, (Vec<DroneBuildInfo>, Vec<DroneBuildInfo>)> {
-    let mut git_sha_to_builds: HashMap<String, (Vec<DroneBuildInfo>, Vec<DroneBuildInfo>)> =
-        HashMap::new();
-
-    for drone_build_list_item in drone1_client.get_builds_paginated() {
-        let git_sha_entry = git_sha_to_builds
-            .entry(drone_build_list_item.git_metadata.git_sha.clone())
-            .or_default();
-        match filter_build(
-            &drone_build_list_item,
-            &window_start,
-            &window_end,
-            &drone1_client,
-            develop,
+/// The instances of --env `name`, which may also redefine `BITGO_ENV`
+fn drone_env(name: &str, mut envs: BTreeMap<String, DroneEnv>) -> Result<DroneEnv, ConfigError> {
+    match envs.remove(name) {
+        Some(drone_env) => Ok(drone_env),
+        None if name == BITGO_ENV => Ok(DroneEnv::default()),
+        None => Err(ConfigError::UnknownEnv {
+            env: name.to_string(),
+        }),
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = match parse_cli() {
+        Ok(cli) => cli,
+        Err(error) => {
+            eprintln!("error: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    env_logger::Builder::new()
+        .filter_level(match cli.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        })
+        .parse_default_env()
+        .init();
+    let run_summary_format = cli.run_summary;
+    let started = Instant::now();
+    let mut run_summary = RunSummary::default();
+    let exit_code = match run(cli, &mut run_summary) {
+        Ok(exit_code) => exit_code,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::FAILURE
+        }
+    };
+    if let Some(RunSummaryFormat::Json) = run_summary_format {
+        run_summary.elapsed_secs = started.elapsed().as_secs_f64();
+        // the summary only holds numbers, so it always serializes
+        eprintln!("{}", serde_json::to_string(&run_summary).unwrap());
+    }
+    exit_code
+}
+
+/// Reads one `owner/name` slug per line, skipping blank lines and `#`
+/// comments
+fn read_repos_file(path: &Path) -> Result<Vec<RepoSlug>, RunError> {
+    let contents = fs::read_to_string(path).map_err(|source| RunError::ReadReposFile {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_number, line)| {
+            line.parse().map_err(|source| RunError::InvalidRepo {
+                path: path.to_path_buf(),
+                line_number,
+                source,
+            })
+        })
+        .collect()
+}
+
+/// Builds a client for one Drone instance with the connection settings shared
+/// by both instances
+fn drone_client(
+    cli: &Cli,
+    repo: &RepoSlug,
+    instance: DroneInstance,
+    url: &Url,
+    token: &str,
+    rate_limiter: Option<&RateLimiter>,
+) -> Result<DroneClient, DroneError> {
+    let mut drone_client =
+        DroneClient::builder(instance, url.clone(), repo.clone(), token.to_string())
+            .retry_policy(RetryPolicy {
+                max_retries: cli.max_retries,
+                ..Default::default()
+            })
+            .timeout(Duration::from_secs(cli.timeout_secs))
+            .connect_timeout(Duration::from_secs(cli.connect_timeout_secs))
+            .pool_max_idle_per_host(cli.pool_max_idle_per_host.unwrap_or(cli.concurrency))
+            .pool_idle_timeout(Duration::from_secs(cli.pool_idle_timeout_secs))
+            .user_agent(cli.user_agent.clone())
+            .request_limit(RequestLimit::new(cli.concurrency));
+    if let Some(ca_cert) = &cli.ca_cert {
+        drone_client = drone_client.ca_cert(ca_cert.clone());
+    }
+    if cli.insecure_skip_tls_verify {
+        drone_client = drone_client.insecure_skip_tls_verify();
+    }
+    if cli.no_compression {
+        drone_client = drone_client.no_compression();
+    }
+    if cli.no_proxy {
+        drone_client = drone_client.no_proxy();
+    } else if let Some(proxy) = &cli.proxy {
+        drone_client = drone_client.proxy(proxy.clone());
+    }
+    if let Some(cache_dir) = &cli.cache_dir {
+        drone_client = drone_client.cache_dir(cache_dir.clone());
+    }
+    if let Some(rate_limiter) = rate_limiter {
+        drone_client = drone_client.rate_limiter(rate_limiter.clone());
+    }
+    if cli.ramp_ms > 0 {
+        drone_client = drone_client.ramp_up(Duration::from_millis(cli.ramp_ms));
+    }
+    drone_client.build()
+}
+
+/// Token of `instance` from the command line, the --env's variable or the
+/// credentials file
+fn instance_token(
+    cli: &Cli,
+    credentials: Option<(&Path, &Credentials)>,
+    instance: DroneInstance,
+) -> Result<String, CredentialsError> {
+    let env = cli.drone_env.token_var(instance);
+    let (token, credentials_token, key) = match instance {
+        DroneInstance::Drone1 => (
+            &cli.drone1_token,
+            credentials.map(|(path, credentials)| (path, credentials.drone1_token.as_deref())),
+            "drone1_token",
+        ),
+        DroneInstance::Drone2 => (
+            &cli.drone2_token,
+            credentials.map(|(path, credentials)| (path, credentials.drone2_token.as_deref())),
+            "drone2_token",
+        ),
+    };
+    let token = token.clone().or_else(|| std::env::var(env).ok());
+    resolve_token(token.as_deref(), credentials_token, key, env)
+}
+
+/// Resolves the token of `instance` and builds its client
+fn instance_client(
+    cli: &Cli,
+    credentials: Option<(&Path, &Credentials)>,
+    repo: &RepoSlug,
+    instance: DroneInstance,
+    rate_limiter: Option<&RateLimiter>,
+) -> Result<DroneClient, RunError> {
+    let token = instance_token(cli, credentials, instance)?;
+    Ok(drone_client(
+        cli,
+        repo,
+        instance,
+        &instance_url(cli, instance),
+        &token,
+        rate_limiter,
+    )?)
+}
+
+fn instance_url(cli: &Cli, instance: DroneInstance) -> Url {
+    match instance {
+        DroneInstance::Drone1 => cli.drone1_url.clone(),
+        DroneInstance::Drone2 => cli.drone2_url.clone(),
+    }
+    .unwrap_or_else(|| cli.drone_env.url(instance))
+}
+
+/// Fails fast on tokens that would only be rejected once requests are
+/// made: a blank token, or, with --strict-tokens, the same token for two
+/// instances at different URLs, which is most likely one token pasted
+/// twice. A missing token is left to the client that needs it.
+fn check_tokens(cli: &Cli, credentials: Option<(&Path, &Credentials)>) -> Result<(), RunError> {
+    let drone1_token = instance_token(cli, credentials, DroneInstance::Drone1);
+    let drone2_token = instance_token(cli, credentials, DroneInstance::Drone2);
+    for token in [&drone1_token, &drone2_token] {
+        if let Err(CredentialsError::Empty { key }) = token {
+            return Err(CredentialsError::Empty { key }.into());
+        }
+    }
+    if let (Ok(drone1_token), Ok(drone2_token)) = (drone1_token, drone2_token) {
+        if drone1_token == drone2_token
+            && instance_url(cli, DroneInstance::Drone1) != instance_url(cli, DroneInstance::Drone2)
+        {
+            if cli.strict_tokens {
+                return Err(RunError::SameToken);
+            }
+            warn!("{}", RunError::SameToken);
+        }
+    }
+    Ok(())
+}
+
+fn run(cli: Cli, run_summary: &mut RunSummary) -> Result<ExitCode, RunError> {
+    if cli.develop {
+        warn!("--develop is deprecated, use --mode develop instead");
+    }
+    let credentials = match &cli.credentials_file {
+        Some(path) => Some((path.as_path(), Credentials::from_file(path)?)),
+        None => None,
+    };
+    let credentials = credentials
+        .as_ref()
+        .map(|(path, credentials)| (*path, credentials));
+    // one limiter for every client, so the limit holds across instances and
+    // repositories
+    let rate_limiter = cli.rate_limit.map(RateLimiter::new);
+    let rate_limiter = rate_limiter.as_ref();
+    // replayed builds need no tokens
+    if cli.replay.is_none() {
+        check_tokens(&cli, credentials)?;
+    }
+
+    if let Some(build_number) = cli.restart {
+        let drone_client = instance_client(
+            &cli,
+            credentials,
+            &cli.repo,
+            cli.restart_instance,
+            rate_limiter,
+        )?;
+        let restarted = drone_client.restart_build(build_number)?;
+        println!(
+            "Restarted {} build {build_number} as build {}: {}",
+            cli.restart_instance, restarted.build_info.number, restarted.build_info.link
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(build_number) = cli.graph {
+        let drone_client = instance_client(
+            &cli,
+            credentials,
+            &cli.repo,
+            DroneInstance::Drone2,
+            rate_limiter,
+        )?;
+        let stage_graph = drone_client.get_build_info(build_number)?.stage_graph();
+        println!("{stage_graph}");
+        match stage_graph.critical_path() {
+            Some((path, elapsed_time)) => {
+                eprintln!("critical path: {} ({elapsed_time}s)", path.join(" -> "))
+            }
+            None => eprintln!("no critical path: the stage dependencies contain a cycle"),
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(build_number) = cli.steps {
+        let drone_client = instance_client(
+            &cli,
+            credentials,
+            &cli.repo,
+            cli.steps_instance,
+            rate_limiter,
+        )?;
+        let drone_build_info = drone_client.get_build_info(build_number)?;
+        write_step_rows(
+            &step_rows(&drone_build_info),
+            cli.file.clone(),
+            cli.format,
+            cli.delimiter,
+        )?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if cli.list_repos {
+        for instance in [DroneInstance::Drone1, DroneInstance::Drone2] {
+            let drone_client =
+                instance_client(&cli, credentials, &cli.repo, instance, rate_limiter)?;
+            for repo_info in drone_client.list_repos()? {
+                if repo_info.active {
+                    println!("{instance}\t{}", repo_info.slug);
+                } else {
+                    println!("{instance}\t{}\tinactive", repo_info.slug);
+                }
+            }
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if cli.count {
+        for instance in [DroneInstance::Drone1, DroneInstance::Drone2] {
+            let drone_client =
+                instance_client(&cli, credentials, &cli.repo, instance, rate_limiter)?;
+            match drone_client.get_build_count()? {
+                Some(build_count) => println!("{instance}\t{build_count}"),
+                None => println!("{instance}\tunknown"),
+            }
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let drone1_only = drone1_only(&cli, credentials);
+    if drone1_only {
+        warn!("No drone2_token given, reporting drone1 builds only");
+    }
+
+    let repos = match &cli.repos_file {
+        Some(path) => read_repos_file(path)?,
+        None => vec![cli.repo.clone()],
+    };
+    let trace = cli
+        .trace_out
+        .as_deref()
+        .map(FilterTrace::create)
+        .transpose()?;
+    if cli.compact {
+        return compact_report(
+            &cli,
+            credentials,
+            &repos,
+            rate_limiter,
+            trace.as_ref(),
+            run_summary,
+        );
+    }
+    if cli.follow {
+        return follow_report(&cli, credentials, rate_limiter, trace.as_ref(), run_summary);
+    }
+    // rows are written out as each chunk of commits is fetched, and only kept
+    // around when they're needed for the summary
+    let report_writer = (!cli.dry_run)
+        .then(|| report_writer(&cli, drone1_only))
+        .transpose()?
+        .map(finish_on_interrupt)
+        .transpose()?;
+    let baseline = cli
+        .baseline
+        .as_deref()
+        .map(|path| Baseline::read(path, cli.delimiter))
+        .transpose()?;
+    let mut dump_writer = cli.dump.as_deref().map(DumpWriter::create).transpose()?;
+    let fail_on_columns = if cli.fail_on_column.is_empty() {
+        StatusColumn::value_variants()
+    } else {
+        &cli.fail_on_column
+    };
+    let report_config = report_config(&cli, drone1_only);
+    let keep_rows = cli.summary || cli.prometheus_out.is_some();
+    let mut rows = Vec::new();
+    let mut failed = false;
+    let mut failed_status_rows = 0;
+    let mut repo_summaries = Vec::new();
+    for repo in &repos {
+        let mut repo_rows = Vec::new();
+        let mut write_rows = |drone1_client: Option<&DroneClient>, commit_sha_to_builds| {
+            let mut chunk_rows = chunk_rows(
+                &cli,
+                &report_config,
+                repo,
+                drone1_client,
+                commit_sha_to_builds,
+                dump_writer.as_mut(),
+                run_summary,
+            )?;
+            if let Some(baseline) = &baseline {
+                for row in &mut chunk_rows {
+                    baseline.apply(row);
+                }
+            }
+            if let Some(report_writer) = &report_writer {
+                let mut report_writer = report_writer.lock().unwrap();
+                let report_writer = report_writer
+                    .as_mut()
+                    .expect("only the Ctrl-C handler takes the report writer");
+                for row in &chunk_rows {
+                    report_writer.write_row(row);
+                }
+                failed_status_rows += chunk_rows
+                    .iter()
+                    .filter(|row| {
+                        fail_on_columns
+                            .iter()
+                            .filter_map(|column| column.status(row))
+                            .any(|status| cli.fail_on_status.contains(&status))
+                    })
+                    .count();
+                report_writer.flush();
+            }
+            if keep_rows {
+                repo_rows.extend(chunk_rows);
+            }
+            Ok(())
+        };
+        match repo_builds(
+            &cli,
+            credentials,
+            repo,
+            rate_limiter,
+            trace.as_ref(),
+            &mut write_rows,
         ) {
-            FilterState::Break => break,
-            FilterState::Continue => continue,
-            FilterState::DroneBuildInfo(drone_build_info) => git_sha_entry.0.push(drone_build_info),
-        }
-    }
-
-    for drone_build_list_item in drone2_client.get_builds_paginated() {
-        let git_sha_entry = git_sha_to_builds
-            .entry(drone_build_list_item.git_metadata.git_sha.clone())
-            .or_default();
-        match filter_build(
-            &drone_build_list_item,
-            &window_start,
-            &window_end,
-            &drone2_client,
-            develop,
+            Ok(skipped_builds) => {
+                run_summary.dropped.unexpected_shape += skipped_builds;
+                if cli.prometheus_out.is_some() {
+                    repo_summaries.push((repo.to_string(), stats::summarize(&repo_rows)));
+                }
+                if cli.summary {
+                    rows.extend(repo_rows);
+                }
+            }
+            // a failing repository shouldn't cost the report of the others,
+            // though the rows written before it failed are kept
+            Err(error) if cli.repos_file.is_some() => {
+                eprintln!("error: {repo}: {error}");
+                failed = true;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    let Some(report_writer) = report_writer else {
+        return Ok(if failed {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        });
+    };
+
+    if cli.summary {
+        eprintln!("{}", stats::summarize(&rows));
+    }
+    let written = report_writer
+        .lock()
+        .unwrap()
+        .take()
+        .expect("only the Ctrl-C handler takes the report writer")
+        .finish()?;
+    run_summary.rows_written = written;
+    if let Some(path) = &cli.prometheus_out {
+        prometheus::write_textfile(path, &repo_summaries)?;
+    }
+    if run_summary.dropped.any() {
+        eprintln!("warning: {}", run_summary.dropped);
+    }
+    if failed {
+        return Ok(ExitCode::FAILURE);
+    }
+    if failed_status_rows > 0 {
+        eprintln!("error: {failed_status_rows} rows have a status given to --fail-on-status");
+        return Ok(ExitCode::from(FAILED_STATUS_EXIT_CODE));
+    }
+    if written == 0 && !cli.allow_empty {
+        eprintln!(
+            "error: no builds found to compare; pass --allow-empty to accept an empty report"
+        );
+        return Ok(ExitCode::from(EMPTY_REPORT_EXIT_CODE));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// The full report's writer, set up from the output options
+fn report_writer(cli: &Cli, drone1_only: bool) -> Result<ReportWriter, RunError> {
+    let report_writer = match (cli.file.clone(), cli.append) {
+        (Some(OutputTarget::File(file)), true) => {
+            ReportWriter::append(file, cli.format, cli.delimiter)?
+        }
+        (_, true) => return Err(RunError::AppendToObject),
+        (file, false) => ReportWriter::new(file, cli.format, cli.delimiter)?,
+    };
+    let mut report_writer = report_writer.time_unit(cli.time_unit);
+    if drone1_only {
+        report_writer = report_writer.drone1_only();
+    }
+    Ok(if cli.columns.is_empty() {
+        report_writer
+    } else {
+        report_writer.columns(cli.columns.clone())
+    })
+}
+
+/// Shares `report_writer` with a Ctrl-C handler that finishes the report
+/// with the rows written so far and exits. It holds the lock until the
+/// process is gone, so it never cuts into a row being written, and the
+/// writer is never found taken otherwise
+fn finish_on_interrupt(
+    report_writer: ReportWriter,
+) -> Result<Arc<Mutex<Option<ReportWriter>>>, ctrlc::Error> {
+    let report_writer = Arc::new(Mutex::new(Some(report_writer)));
+    let handler_report_writer = Arc::clone(&report_writer);
+    ctrlc::set_handler(move || {
+        let mut report_writer = handler_report_writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(report_writer) = report_writer.take() {
+            match report_writer.finish() {
+                Ok(written) => eprintln!("interrupted: wrote {written} rows"),
+                Err(error) => eprintln!("interrupted: {}", RunError::Output(error)),
+            }
+        }
+        process::exit(INTERRUPTED_EXIT_CODE);
+    })?;
+    Ok(report_writer)
+}
+
+/// Polls --repo for finished builds every --interval-secs and writes the
+/// rows of the commits built on both instances as they turn up, until the
+/// Ctrl-C handler finishes the report. Rather than starting each walk after
+/// the last build seen, which would lose builds still running when a newer
+/// one finishes, every poll walks the whole window again and leaves out the
+/// builds fetched before
+fn follow_report(
+    cli: &Cli,
+    credentials: Option<(&Path, &Credentials)>,
+    rate_limiter: Option<&RateLimiter>,
+    trace: Option<&FilterTrace>,
+    run_summary: &mut RunSummary,
+) -> Result<ExitCode, RunError> {
+    let drone1_only = drone1_only(cli, credentials);
+    let (drone1_client, drone2_client) =
+        repo_clients(cli, credentials, &cli.repo, rate_limiter, drone1_only)?;
+    let report_config = report_config(cli, drone1_only);
+    let report_writer = finish_on_interrupt(report_writer(cli, drone1_only)?)?;
+    let mut fetched_builds = HashSet::new();
+    // builds of commits still waiting for the other instance
+    let mut pending = CommitBuildMap::new();
+    let mut reported = HashSet::new();
+    loop {
+        let (window_start, window_end) = get_window_bounds(cli)?;
+        let select_config = SelectConfig {
+            skip_builds: fetched_builds.clone(),
+            ..select_config(cli, window_start, window_end, trace)
+        };
+        match drone_build_map(&drone1_client, drone2_client.as_ref(), &select_config) {
+            Ok(commit_sha_to_builds) => {
+                run_summary.record_builds(&commit_sha_to_builds);
+                for (git_sha, (drone1_builds, drone2_builds)) in commit_sha_to_builds {
+                    fetched_builds.extend(
+                        drone1_builds.iter().map(|drone_build| {
+                            (DroneInstance::Drone1, drone_build.build_info.number)
+                        }),
+                    );
+                    fetched_builds.extend(
+                        drone2_builds.iter().map(|drone_build| {
+                            (DroneInstance::Drone2, drone_build.build_info.number)
+                        }),
+                    );
+                    // reruns of a reported commit are left out
+                    if reported.contains(&git_sha) {
+                        continue;
+                    }
+                    let builds = pending.entry(git_sha).or_default();
+                    builds.0.extend(drone1_builds);
+                    builds.1.extend(drone2_builds);
+                }
+            }
+            Err(error) => warn!("Failed to poll for builds: {error}"),
+        }
+        run_summary.dropped.unexpected_shape = drone1_client.skipped_builds()
+            + drone2_client
+                .as_ref()
+                .map_or(0, DroneClient::skipped_builds);
+
+        let ready: Vec<String> = pending
+            .iter()
+            .filter(|(_, (drone1_builds, drone2_builds))| {
+                !drone1_builds.is_empty() && (drone1_only || !drone2_builds.is_empty())
+            })
+            .map(|(git_sha, _)| git_sha.clone())
+            .collect();
+        let ready: CommitBuildMap = ready
+            .into_iter()
+            .filter_map(|git_sha| {
+                let builds = pending.remove(&git_sha)?;
+                reported.insert(git_sha.clone());
+                Some((git_sha, builds))
+            })
+            .collect();
+        let mut rows = build_rows(ready, &report_config, &mut run_summary.dropped);
+        if cli.with_error_tail {
+            add_await_error_tails(&drone1_client, &mut rows);
+        }
+        if cli.dump_logs {
+            dump_failed_step_logs(&drone1_client, &rows, cli.logs_dir.as_deref());
+        }
+        {
+            let mut report_writer = report_writer.lock().unwrap();
+            let report_writer = report_writer
+                .as_mut()
+                .expect("only the Ctrl-C handler takes the report writer");
+            for row in &rows {
+                report_writer.write_row(row);
+            }
+            report_writer.flush();
+        }
+        run_summary.rows_written += rows.len();
+        thread::sleep(Duration::from_secs(cli.interval_secs));
+    }
+}
+
+/// Writes the --compact report of every repository at once, exiting like the
+/// full report does
+fn compact_report(
+    cli: &Cli,
+    credentials: Option<(&Path, &Credentials)>,
+    repos: &[RepoSlug],
+    rate_limiter: Option<&RateLimiter>,
+    trace: Option<&FilterTrace>,
+    run_summary: &mut RunSummary,
+) -> Result<ExitCode, RunError> {
+    let report_config = report_config(cli, drone1_only(cli, credentials));
+    let mut rows = Vec::new();
+    let mut failed = false;
+    for repo in repos {
+        let mut collect_rows = |_: Option<&DroneClient>, commit_sha_to_builds| {
+            run_summary.record_builds(&commit_sha_to_builds);
+            let mut chunk_rows = compact_rows(
+                commit_sha_to_builds,
+                &report_config,
+                &mut run_summary.dropped,
+            );
+            if cli.repos_file.is_some() {
+                for row in &mut chunk_rows {
+                    row.repo = Some(repo.to_string());
+                }
+            }
+            rows.extend(chunk_rows);
+            Ok(())
+        };
+        match repo_builds(
+            cli,
+            credentials,
+            repo,
+            rate_limiter,
+            trace,
+            &mut collect_rows,
         ) {
-            FilterState::Break => break,
-            FilterState::Continue => continue,
-            FilterState::DroneBuildInfo(drone_build_info) => git_sha_entry.1.push(drone_build_info),
+            Ok(skipped_builds) => run_summary.dropped.unexpected_shape += skipped_builds,
+            // a failing repository shouldn't cost the report of the others
+            Err(error) if cli.repos_file.is_some() => {
+                eprintln!("error: {repo}: {error}");
+                failed = true;
+            }
+            Err(error) => return Err(error),
         }
     }
-    git_sha_to_builds
+    if cli.dry_run || failed {
+        return Ok(if failed {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        });
+    }
+
+    write_compact_rows(&rows, cli.file.clone(), cli.format, cli.delimiter)?;
+    run_summary.rows_written = rows.len();
+    if run_summary.dropped.any() {
+        eprintln!("warning: {}", run_summary.dropped);
+    }
+    if rows.is_empty() && !cli.allow_empty {
+        eprintln!(
+            "error: no builds found to compare; pass --allow-empty to accept an empty report"
+        );
+        return Ok(ExitCode::from(EMPTY_REPORT_EXIT_CODE));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Without a Drone2 token there's nothing to compare with, so Drone1 builds
+/// are reported on their own; replays bring their own builds
+fn drone1_only(cli: &Cli, credentials: Option<(&Path, &Credentials)>) -> bool {
+    cli.replay.is_none()
+        && matches!(
+            instance_token(cli, credentials, DroneInstance::Drone2),
+            Err(CredentialsError::Missing { .. } | CredentialsError::MissingKey { .. })
+        )
+}
+
+fn report_config(cli: &Cli, drone1_only: bool) -> ReportConfig {
+    ReportConfig {
+        mode: cli.mode(),
+        await_threshold_secs: cli.await_threshold_secs,
+        await_abs: cli.await_abs,
+        skew_tolerance_secs: cli.skew_tolerance_secs,
+        pr_stage: cli.pr_stage.clone(),
+        develop_stage: cli.develop_stage.clone(),
+        tag_stage: cli.tag_stage.clone(),
+        unit_test_step: cli.unit_test_step.clone(),
+        await_step: cli.await_step.clone(),
+        compare: cli.compare.clone(),
+        include_skipped: cli.include_skipped,
+        normalize_status: cli.normalize_status,
+        pick: cli.pick,
+        statuses: cli.status.clone(),
+        system_stage_regex: cli.system_stage_regex.clone(),
+        stage_type: cli.stage_type.clone(),
+        drone1_only,
+    }
 }
 
-fn main() {
-    let cli = Cli::parse();
+/// Compares a chunk of the builds of `repo`, fetched with `drone1_client`
+/// unless replayed
+fn chunk_rows(
+    cli: &Cli,
+    report_config: &ReportConfig,
+    repo: &RepoSlug,
+    drone1_client: Option<&DroneClient>,
+    commit_sha_to_builds: CommitBuildMap,
+    dump_writer: Option<&mut DumpWriter>,
+    run_summary: &mut RunSummary,
+) -> Result<Vec<Row>, RunError> {
+    run_summary.record_builds(&commit_sha_to_builds);
+    if let Some(dump_writer) = dump_writer {
+        dump_writer.write_builds(&commit_sha_to_builds)?;
+    }
+
+    let mut rows = build_rows(
+        commit_sha_to_builds,
+        report_config,
+        &mut run_summary.dropped,
+    );
+    if let (Some(drone1_client), true) = (drone1_client, cli.with_error_tail) {
+        add_await_error_tails(drone1_client, &mut rows);
+    }
+    if let (Some(drone1_client), true) = (drone1_client, cli.dump_logs) {
+        dump_failed_step_logs(drone1_client, &rows, cli.logs_dir.as_deref());
+    }
+    if cli.repos_file.is_some() {
+        for row in &mut rows {
+            row.repo = Some(repo.to_string());
+        }
+    }
+    Ok(rows)
+}
+
+/// Takes the builds of a chunk of commits along with the Drone1 client they
+/// came from, if they weren't replayed
+type OnBuilds<'a> = dyn FnMut(Option<&DroneClient>, CommitBuildMap) -> Result<(), RunError> + 'a;
+
+/// Hands the builds of one repository to compare to `on_builds`, either
+/// replayed or fetched along with the Drone1 client they came from, a chunk
+/// of commits at a time for a window; nothing is handed over after
+/// reporting the requests that would be made with --dry-run. Returns the
+/// number of builds skipped for their shape.
+fn repo_builds(
+    cli: &Cli,
+    credentials: Option<(&Path, &Credentials)>,
+    repo: &RepoSlug,
+    rate_limiter: Option<&RateLimiter>,
+    trace: Option<&FilterTrace>,
+    on_builds: &mut OnBuilds,
+) -> Result<usize, RunError> {
+    // replayed builds need neither tokens nor a connection to Drone
+    if let Some(path) = &cli.replay {
+        on_builds(None, read_replay(path, cli.correlate_by)?)?;
+        return Ok(0);
+    }
+
+    let (drone1_client, drone2_client) = repo_clients(
+        cli,
+        credentials,
+        repo,
+        rate_limiter,
+        drone1_only(cli, credentials),
+    )?;
+
+    match &cli.commit {
+        Some(sha) => {
+            let max_builds = cli.max_builds.unwrap_or(COMMIT_MAX_BUILDS);
+            let drone1_builds = commit_builds(
+                &drone1_client,
+                DroneInstance::Drone1,
+                sha,
+                max_builds,
+                cli.concurrency,
+                cli.compact,
+            )?;
+            // Drone2 is searched for the full sha Drone1 resolved the prefix
+            // to, so both sides are builds of the same commit
+            let git_sha = drone1_builds[0].build_info.git_metadata.git_sha.clone();
+            let drone2_builds = match &drone2_client {
+                Some(drone2_client) => commit_builds(
+                    drone2_client,
+                    DroneInstance::Drone2,
+                    &git_sha,
+                    max_builds,
+                    cli.concurrency,
+                    cli.compact,
+                )?,
+                None => Vec::new(),
+            };
+            on_builds(
+                Some(&drone1_client),
+                CommitBuildMap::from([(git_sha, (drone1_builds, drone2_builds))]),
+            )?;
+        }
+        None => {
+            let (window_start, window_end) = get_window_bounds(cli)?;
+
+            // window_start and window_end are ordered from the perspective of the start
+            // of a drone build list, where builds are in decreasing order from "now"
+            // into the past.
+            // If this is a list of drone builds, builds compared by this tool
+            // would include builds that were created after 'window_end' and builds
+            // which finished before 'window_start'; any build is fully contained
+            // within the window will be selected for comparison, and with
+            // --window-mode lenient, builds created before 'window_end' too.
+            // In the example below, only builds 4568, 4569, and 4570
+            // will be selected, or 4567 as well when lenient.
+            // (past)-4567---4568---4569---4570---4571---*---*---(now)
+            //         ||     ||     ||     ||     ||
+            //         vv     ||     vv     ||     ||
+            //       |-----|  ||  |-------| ||     ||
+            //                vv            vv     ||
+            //              |--------| |------|    vv
+            //                              |---------|
+            //            ^                     ^                   ^
+            //            |------- 5 hrs -------|<------ 3 hrs -----|
+            //            |   window_duration   |    window_offset  |
+            //            |                     |                   |
+            //        window_end           window_start
+
+            let select_config = select_config(cli, window_start, window_end, trace);
+            if cli.dry_run {
+                let drone_clients = [
+                    (DroneInstance::Drone1, Some(&drone1_client)),
+                    (DroneInstance::Drone2, drone2_client.as_ref()),
+                ];
+                for (instance, drone_client) in drone_clients
+                    .into_iter()
+                    .filter_map(|(instance, drone_client)| Some((instance, drone_client?)))
+                {
+                    let selected = select_build_list(drone_client, &select_config)?;
+                    // compact reports take everything from the build list
+                    let detail_requests = if cli.compact {
+                        0
+                    } else {
+                        selected.builds.len()
+                    };
+                    let label = match &cli.repos_file {
+                        Some(_) => format!("{repo} {instance}"),
+                        None => instance.to_string(),
+                    };
+                    eprintln!(
+                        "{label}: {} build list requests made, {} build detail requests would follow",
+                        selected.pages_fetched, detail_requests
+                    );
+                }
+                return Ok(0);
+            }
+
+            drone_build_map_chunks(
+                &drone1_client,
+                drone2_client.as_ref(),
+                &select_config,
+                COMMITS_PER_CHUNK,
+                |commit_sha_to_builds| on_builds(Some(&drone1_client), commit_sha_to_builds),
+            )?;
+        }
+    }
+    Ok([Some(&drone1_client), drone2_client.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(DroneClient::skipped_builds)
+        .sum())
+}
+
+/// The clients of both instances for `repo`, without Drone2 for
+/// `drone1_only`, once the repository is known to be active on each
+fn repo_clients(
+    cli: &Cli,
+    credentials: Option<(&Path, &Credentials)>,
+    repo: &RepoSlug,
+    rate_limiter: Option<&RateLimiter>,
+    drone1_only: bool,
+) -> Result<(DroneClient, Option<DroneClient>), RunError> {
     let drone1_client =
-        drone::DroneClient::new_with_credentials(BITGO_DRONE1_URL, cli.drone1_token.clone());
-    let drone2_client =
-        drone::DroneClient::new_with_credentials(BITGO_DRONE2_URL, cli.drone2_token.clone());
-
-    let (window_start, window_end) = get_window_bounds(&cli);
-
-    // window_start and window_end are ordered from the perspective of the start
-    // of a drone build list, where builds are in decreasing order from "now"
-    // into the past.
-    // If this is a list of drone builds, builds compared by this tool
-    // would include builds that were created after 'window_end' and builds
-    // which finished before 'window_start'; any build is fully contained
-    // within the window will be selected for comparison.
-    // In the example below, only builds 4568, 4569, and 4570
-    // will be selected.
-    // (past)-4567---4568---4569---4570---4571---*---*---(now)
-    //         ||     ||     ||     ||     ||
-    //         vv     ||     vv     ||     ||
-    //       |-----|  ||  |-------| ||     ||
-    //                vv            vv     ||
-    //              |--------| |------|    vv
-    //                              |---------|
-    //            ^                     ^                   ^
-    //            |------- 5 hrs -------|<------ 3 hrs -----|
-    //            |   window_duration   |    window_offset  |
-    //            |                     |                   |
-    //        window_end           window_start
-
-    let commit_sha_to_builds = drone_build_map(
+        instance_client(cli, credentials, repo, DroneInstance::Drone1, rate_limiter)?;
+    let drone2_client = (!drone1_only)
+        .then(|| instance_client(cli, credentials, repo, DroneInstance::Drone2, rate_limiter))
+        .transpose()?;
+    // an inactive repository has no builds, which would otherwise only show
+    // up as an empty report
+    if !cli.allow_inactive {
+        for drone_client in [Some(&drone1_client), drone2_client.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            if !drone_client.get_repo()?.active {
+                return Err(RunError::InactiveRepo {
+                    repo: repo.clone(),
+                    instance: drone_client.instance(),
+                });
+            }
+        }
+    }
+    Ok((drone1_client, drone2_client))
+}
+
+fn select_config<'trace>(
+    cli: &Cli,
+    window_start: SystemTime,
+    window_end: SystemTime,
+    trace: Option<&'trace FilterTrace>,
+) -> SelectConfig<'trace> {
+    SelectConfig {
         window_start,
         window_end,
-        drone1_client,
-        drone2_client,
-        cli.develop,
-    );
+        window_mode: cli.window_mode,
+        mode: cli.mode(),
+        concurrency: cli.concurrency,
+        max_builds: cli.max_builds,
+        since_build: cli.since_build,
+        build_range: cli.build_range.clone(),
+        head_only: cli.head_only,
+        skip_builds: HashSet::new(),
+        page_size: cli.page_size,
+        pagination: cli.pagination,
+        target_branch: cli.target_branch.clone(),
+        source_branch: cli.source_branch.clone(),
+        // log lines would tear through the spinner, so it's only shown
+        // without -v
+        progress: !cli.quiet && cli.verbose == 0,
+        authors: cli
+            .author
+            .iter()
+            .map(|author| author.to_lowercase())
+            .collect(),
+        compact: cli.compact,
+        correlation: cli.correlate_by,
+        trace,
+    }
+}
 
-    crate::csv::write_csv(commit_sha_to_builds, cli.file, cli.develop);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from([&["cuddly-robot"], args].concat()).unwrap()
+    }
+
+    #[test]
+    fn window_bounds_count_back_from_now() {
+        let before = SystemTime::now();
+        let (window_start, window_end) = get_window_bounds(&cli(&["5", "-w", "3"])).unwrap();
+        assert_eq!(
+            window_start.duration_since(window_end).unwrap(),
+            Duration::from_secs(5 * 60 * 60)
+        );
+        let offset = before.duration_since(window_start).unwrap();
+        assert!(offset >= Duration::from_secs(3 * 60 * 60) - Duration::from_secs(60));
+        assert!(offset <= Duration::from_secs(3 * 60 * 60) + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn window_bounds_reaching_too_far_back_are_rejected() {
+        let max = u64::MAX.to_string();
+        assert!(matches!(
+            get_window_bounds(&cli(&["5", "-w", &max])),
+            Err(WindowError::OffsetTooLarge {
+                window_offset: u64::MAX
+            })
+        ));
+        assert!(matches!(
+            get_window_bounds(&cli(&[&max])),
+            Err(WindowError::DurationTooLarge {
+                window_duration: u64::MAX
+            })
+        ));
+        // fits in a Duration, but reaches further back than SystemTime can
+        let hours = (u64::MAX / (60 * 60)).to_string();
+        assert!(matches!(
+            get_window_bounds(&cli(&[&hours])),
+            Err(WindowError::DurationTooLarge { .. })
+        ));
+        assert_eq!(hours_before(UNIX_EPOCH, u64::MAX), None);
+    }
+
+    #[test]
+    fn zero_window_duration_is_rejected() {
+        let error = Cli::try_parse_from(["cuddly-robot", "0"]).err().unwrap();
+        assert_eq!(error.kind(), clap::ErrorKind::ValueValidation);
+        assert!(Cli::try_parse_from(["cuddly-robot", "1"]).is_ok());
+    }
+
+    #[test]
+    fn drone_envs_come_from_the_config_file() {
+        let path = Path::new("cuddly-robot.toml");
+        let config: toml::value::Table = toml::from_str(
+            r#"
+            [envs.staging]
+            drone1_url = "https://drone.example.com"
+            drone2_url = "https://drone2.example.com"
+            drone2_token_var = "STAGING_DRONE2_TOKEN"
+            "#,
+        )
+        .unwrap();
+        let envs = config_envs(path, config["envs"].clone()).unwrap();
+
+        let staging = drone_env("staging", envs.clone()).unwrap();
+        assert_eq!(
+            staging.url(DroneInstance::Drone1).as_str(),
+            "https://drone.example.com/"
+        );
+        assert_eq!(
+            staging.url(DroneInstance::Drone2).as_str(),
+            "https://drone2.example.com/"
+        );
+        assert_eq!(staging.token_var(DroneInstance::Drone1), "DRONE1_TOKEN");
+        assert_eq!(
+            staging.token_var(DroneInstance::Drone2),
+            "STAGING_DRONE2_TOKEN"
+        );
+        assert_eq!(
+            drone_env(BITGO_ENV, envs.clone()).unwrap(),
+            DroneEnv::default()
+        );
+        assert!(matches!(
+            drone_env("dev", envs),
+            Err(ConfigError::UnknownEnv { env }) if env == "dev"
+        ));
+    }
+
+    #[test]
+    fn drone_envs_need_both_urls() {
+        let config: toml::value::Table = toml::from_str(
+            r#"
+            [envs.staging]
+            drone1_url = "https://drone.example.com"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            config_envs(Path::new("cuddly-robot.toml"), config["envs"].clone()),
+            Err(ConfigError::InvalidEnvs { .. })
+        ));
+    }
+
+    #[test]
+    fn bitgo_is_the_default_env() {
+        let cli = cli(&["5"]);
+        assert_eq!(cli.env, BITGO_ENV);
+        assert_eq!(
+            instance_url(&cli, DroneInstance::Drone1).as_str(),
+            "https://drone.bitgo-dev.com/"
+        );
+        assert_eq!(
+            instance_url(&cli, DroneInstance::Drone2).as_str(),
+            "https://drone2.bitgo-ci.com/"
+        );
+        assert_eq!(
+            cli.drone_env.token_var(DroneInstance::Drone1),
+            "DRONE1_TOKEN"
+        );
+        assert_eq!(
+            cli.drone_env.token_var(DroneInstance::Drone2),
+            "DRONE2_TOKEN"
+        );
+    }
 }
@@ -0,0 +1,168 @@
+use derive_more::{Display, Error};
+use std::fs::File;
+use std::io::{self, BufWriter, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Where a report goes once it's complete. The report is written through
+/// `Write`, and `finish` is called once after the last row, giving sinks
+/// that can't take a partial report, like an S3 object, the chance to
+/// upload it.
+pub trait OutputSink: Write + Send {
+    /// Flushes by default
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl OutputSink for Stdout {}
+
+impl OutputSink for BufWriter<File> {}
+
+#[derive(Debug, Display, Error)]
+pub enum InvalidOutputTarget {
+    #[cfg(feature = "s3")]
+    #[display(fmt = "invalid S3 target '{}', expected 's3://bucket/key'", _0)]
+    S3(#[error(not(source))] String),
+    #[cfg(not(feature = "s3"))]
+    #[display(
+        fmt = "writing to '{}' needs cuddly-robot built with the s3 feature",
+        _0
+    )]
+    NoS3Support(#[error(not(source))] String),
+}
+
+/// A report destination as given to --file: `s3://bucket/key` for an S3
+/// object with the `s3` feature, and a local path otherwise
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputTarget {
+    File(PathBuf),
+    #[cfg(feature = "s3")]
+    S3 {
+        bucket: String,
+        key: String,
+    },
+}
+
+impl OutputTarget {
+    /// `None` for targets that aren't local files
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            OutputTarget::File(path) => Some(path),
+            #[cfg(feature = "s3")]
+            OutputTarget::S3 { .. } => None,
+        }
+    }
+
+    /// Creates the file, or truncates it; S3 objects can't fail before
+    /// they're finished
+    pub fn open(self) -> io::Result<Box<dyn OutputSink>> {
+        Ok(match self {
+            OutputTarget::File(path) => {
+                let file = File::create(&path).map_err(|error| {
+                    io::Error::new(
+                        error.kind(),
+                        format!("failed to create '{}': {error}", path.display()),
+                    )
+                })?;
+                Box::new(BufWriter::new(file))
+            }
+            #[cfg(feature = "s3")]
+            OutputTarget::S3 { bucket, key } => Box::new(s3::S3Object::new(bucket, key)),
+        })
+    }
+}
+
+impl FromStr for OutputTarget {
+    type Err = InvalidOutputTarget;
+
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        match target.strip_prefix("s3://") {
+            Some(location) => s3_target(target, location),
+            None => Ok(OutputTarget::File(PathBuf::from(target))),
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+fn s3_target(target: &str, location: &str) -> Result<OutputTarget, InvalidOutputTarget> {
+    match location.split_once('/') {
+        Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => Ok(OutputTarget::S3 {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        }),
+        _ => Err(InvalidOutputTarget::S3(target.to_string())),
+    }
+}
+
+#[cfg(not(feature = "s3"))]
+fn s3_target(target: &str, _location: &str) -> Result<OutputTarget, InvalidOutputTarget> {
+    Err(InvalidOutputTarget::NoS3Support(target.to_string()))
+}
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use super::OutputSink;
+    use aws_sdk_s3::error::DisplayErrorContext;
+    use aws_sdk_s3::primitives::ByteStream;
+    use std::io::{self, Write};
+    use std::mem;
+
+    /// Keeps the report in memory and uploads it in one request on `finish`,
+    /// with credentials and region taken from the environment like the AWS
+    /// CLI does; nothing reaches the bucket before then
+    pub(super) struct S3Object {
+        bucket: String,
+        key: String,
+        body: Vec<u8>,
+    }
+
+    impl S3Object {
+        pub(super) fn new(bucket: String, key: String) -> Self {
+            S3Object {
+                bucket,
+                key,
+                body: Vec::new(),
+            }
+        }
+    }
+
+    impl Write for S3Object {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.body.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl OutputSink for S3Object {
+        fn finish(&mut self) -> io::Result<()> {
+            let body = ByteStream::from(mem::take(&mut self.body));
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime
+                .block_on(async {
+                    let config = aws_config::load_from_env().await;
+                    aws_sdk_s3::Client::new(&config)
+                        .put_object()
+                        .bucket(&self.bucket)
+                        .key(&self.key)
+                        .body(body)
+                        .send()
+                        .await
+                })
+                .map(|_| ())
+                .map_err(|error| {
+                    io::Error::other(format!(
+                        "failed to upload report to 's3://{}/{}': {}",
+                        self.bucket,
+                        self.key,
+                        DisplayErrorContext(error)
+                    ))
+                })
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::drone::DroneInstance;
+use crate::stats::{ElapsedStats, Summary};
+use derive_more::{Display, Error};
+
+#[derive(Debug, Display, Error)]
+#[display(
+    fmt = "failed to write Prometheus metrics '{}': {}",
+    "path.display()",
+    source
+)]
+pub struct PrometheusError {
+    path: PathBuf,
+    source: io::Error,
+}
+
+/// The summary of each repository's rows in the Prometheus text exposition
+/// format, labelled with the repository and, for elapsed times, the Drone
+/// instance they were measured on
+pub fn render(summaries: &[(String, Summary)]) -> String {
+    let mut metrics = String::new();
+    // writing to a String can't fail
+    write_metrics(&mut metrics, summaries).unwrap();
+    metrics
+}
+
+fn write_metrics(metrics: &mut String, summaries: &[(String, Summary)]) -> fmt::Result {
+    writeln!(metrics, "# HELP cuddly_robot_rows Rows in the report.")?;
+    writeln!(metrics, "# TYPE cuddly_robot_rows gauge")?;
+    for (repo, summary) in summaries {
+        writeln!(
+            metrics,
+            "cuddly_robot_rows{{repo=\"{}\"}} {}",
+            escape(repo),
+            summary.rows
+        )?;
+    }
+
+    writeln!(
+        metrics,
+        "# HELP cuddly_robot_elapsed_seconds Drone1 unit test step and Drone2 build times."
+    )?;
+    writeln!(metrics, "# TYPE cuddly_robot_elapsed_seconds summary")?;
+    for (repo, summary) in summaries {
+        let elapsed_stats = [
+            (
+                DroneInstance::Drone1,
+                &summary.drone1_unit_test_elapsed_time,
+            ),
+            (DroneInstance::Drone2, &summary.drone2_total_elapsed_time),
+        ];
+        for (instance, stats) in elapsed_stats {
+            if let Some(stats) = stats {
+                write_elapsed_stats(metrics, repo, instance, stats)?;
+            }
+        }
+    }
+
+    writeln!(
+        metrics,
+        "# HELP cuddly_robot_await_threshold_breach_ratio Fraction of rows whose await step \
+         finished later than the await threshold after the unit test step started."
+    )?;
+    writeln!(
+        metrics,
+        "# TYPE cuddly_robot_await_threshold_breach_ratio gauge"
+    )?;
+    for (repo, summary) in summaries {
+        if let Some(ratio) = summary.await_threshold_breach_ratio {
+            writeln!(
+                metrics,
+                "cuddly_robot_await_threshold_breach_ratio{{repo=\"{}\"}} {ratio}",
+                escape(repo)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_elapsed_stats(
+    metrics: &mut String,
+    repo: &str,
+    instance: DroneInstance,
+    stats: &ElapsedStats,
+) -> fmt::Result {
+    let labels = format!("repo=\"{}\",instance=\"{instance}\"", escape(repo));
+    for (quantile, value) in [
+        ("0.5", stats.median),
+        ("0.9", stats.p90),
+        ("0.99", stats.p99),
+    ] {
+        writeln!(
+            metrics,
+            "cuddly_robot_elapsed_seconds{{{labels},quantile=\"{quantile}\"}} {value}"
+        )?;
+    }
+    writeln!(
+        metrics,
+        "cuddly_robot_elapsed_seconds_sum{{{labels}}} {}",
+        stats.mean * stats.count as f64
+    )?;
+    writeln!(
+        metrics,
+        "cuddly_robot_elapsed_seconds_count{{{labels}}} {}",
+        stats.count
+    )
+}
+
+/// Escapes a label value as the exposition format requires
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Writes `render(summaries)` to `path` through a temporary file in the same
+/// directory that is then renamed over it, so a scrape never reads a
+/// partially written file. node-exporter's textfile collector only reads
+/// `*.prom` files, so it ignores the temporary one.
+pub fn write_textfile(path: &Path, summaries: &[(String, Summary)]) -> Result<(), PrometheusError> {
+    let mut temp_file_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_file_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_file_name);
+    fs::write(&temp_path, render(summaries))
+        .and_then(|()| fs::rename(&temp_path, path))
+        .map_err(|source| PrometheusError {
+            path: path.to_path_buf(),
+            source,
+        })
+}
@@ -0,0 +1,150 @@
+use crate::drone::{CommitBuildMap, Correlation, DroneBuildInfo, DroneInstance};
+use derive_more::{Display, Error};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Display, Error)]
+pub enum ReplayError {
+    #[display(fmt = "failed to read replay file '{}': {}", "path.display()", source)]
+    Read { path: PathBuf, source: io::Error },
+    /// `line_number` is 0 when the file holds a JSON array
+    #[display(
+        fmt = "failed to parse replay file '{}' line {}: {}",
+        "path.display()",
+        line_number,
+        source
+    )]
+    Parse {
+        path: PathBuf,
+        line_number: usize,
+        source: serde_json::Error,
+    },
+    #[display(
+        fmt = "replay file '{}' holds build {} which does not have the shape of a {} build",
+        "path.display()",
+        build_number,
+        instance
+    )]
+    UnexpectedShape {
+        path: PathBuf,
+        #[error(not(source))]
+        build_number: u32,
+        instance: DroneInstance,
+    },
+}
+
+#[derive(Debug, Display, Error)]
+pub enum DumpError {
+    #[display(fmt = "failed to create dump file '{}': {}", "path.display()", source)]
+    Create { path: PathBuf, source: io::Error },
+    #[display(fmt = "failed to write dump file '{}': {}", "path.display()", source)]
+    Write { path: PathBuf, source: io::Error },
+}
+
+/// A fetched build along with the Drone instance it was fetched from
+#[derive(Debug, Deserialize)]
+pub struct DumpedBuild {
+    pub instance: DroneInstance,
+    pub build: DroneBuildInfo,
+}
+
+/// `DumpedBuild` without taking ownership of the build
+#[derive(Serialize)]
+struct DumpedBuildRef<'build> {
+    instance: DroneInstance,
+    build: &'build DroneBuildInfo,
+}
+
+/// Writes fetched builds as JSON Lines of `DumpedBuild`s, which `read_replay`
+/// reads back
+pub struct DumpWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl DumpWriter {
+    pub fn create(path: &Path) -> Result<Self, DumpError> {
+        let file = File::create(path).map_err(|source| DumpError::Create {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Ok(DumpWriter {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Appends every build of `commit_build_map` and flushes them to disk, so
+    /// they're kept even if the run fails afterwards
+    pub fn write_builds(&mut self, commit_build_map: &CommitBuildMap) -> Result<(), DumpError> {
+        let write_error = |source| DumpError::Write {
+            path: self.path.clone(),
+            source,
+        };
+        for (drone1_builds, drone2_builds) in commit_build_map.values() {
+            let dumped_builds = drone1_builds
+                .iter()
+                .map(|build| (DroneInstance::Drone1, build))
+                .chain(
+                    drone2_builds
+                        .iter()
+                        .map(|build| (DroneInstance::Drone2, build)),
+                );
+            for (instance, build) in dumped_builds {
+                serde_json::to_writer(&mut self.writer, &DumpedBuildRef { instance, build })
+                    .map_err(io::Error::from)
+                    .map_err(write_error)?;
+                writeln!(self.writer).map_err(write_error)?;
+            }
+        }
+        self.writer.flush().map_err(write_error)
+    }
+}
+
+/// Reads builds previously fetched from Drone, given either as a JSON array
+/// of `DumpedBuild`s or as one per line, and groups them by `correlation`
+/// just like `drone_build_map` does
+pub fn read_replay(path: &Path, correlation: Correlation) -> Result<CommitBuildMap, ReplayError> {
+    let contents = fs::read_to_string(path).map_err(|source| ReplayError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let parse_error = |line_number, source| ReplayError::Parse {
+        path: path.to_path_buf(),
+        line_number,
+        source,
+    };
+    let dumped_builds: Vec<DumpedBuild> = if contents.trim_start().starts_with('[') {
+        serde_json::from_str(&contents).map_err(|source| parse_error(0, source))?
+    } else {
+        contents
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line))
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(line_number, line)| {
+                serde_json::from_str(line).map_err(|source| parse_error(line_number, source))
+            })
+            .collect::<Result<_, _>>()?
+    };
+
+    let mut commit_build_map = CommitBuildMap::new();
+    for DumpedBuild { instance, build } in dumped_builds {
+        let build_number = build.build_info.number;
+        let build = build
+            .for_instance(instance)
+            .ok_or_else(|| ReplayError::UnexpectedShape {
+                path: path.to_path_buf(),
+                build_number,
+                instance,
+            })?;
+        let builds = commit_build_map.entry(correlation.key(&build)).or_default();
+        match instance {
+            DroneInstance::Drone1 => builds.0.push(build),
+            DroneInstance::Drone2 => builds.1.push(build),
+        }
+    }
+    Ok(commit_build_map)
+}
@@ -0,0 +1,890 @@
+use crate::drone::{
+    BuildMode, CommitBuildMap, Correlation, DroneBuildInfo, DroneBuildListItem, DroneClient,
+    DroneError, DroneEvent, DroneInstance, DroneStatus, DroneTime, Pagination,
+};
+use clap::ValueEnum;
+use derive_more::{Display, Error};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{debug, info, warn};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Settings controlling which builds are picked from each Drone instance
+pub struct SelectConfig<'trace> {
+    pub window_start: SystemTime,
+    pub window_end: SystemTime,
+    /// Whether builds created before `window_end` are selected
+    pub window_mode: WindowMode,
+    pub mode: BuildMode,
+    /// Build details fetched at the same time from each instance; selection
+    /// happens before fetching, so this applies to every `mode` alike
+    pub concurrency: usize,
+    pub max_builds: Option<usize>,
+    /// Only consider builds numbered above this
+    pub since_build: Option<u32>,
+    /// Only consider builds numbered within this range
+    pub build_range: Option<RangeInclusive<u32>>,
+    /// Keep only the highest-numbered selected build of each git sha, before
+    /// any details are fetched
+    pub head_only: bool,
+    /// Builds left out as if they weren't listed, by instance and number
+    pub skip_builds: HashSet<(DroneInstance, u32)>,
+    pub page_size: usize,
+    pub pagination: Pagination,
+    pub target_branch: Option<Regex>,
+    pub source_branch: Option<Regex>,
+    /// Whether to show a spinner while fetching build details
+    pub progress: bool,
+    /// Lowercased author logins to restrict the comparison to; empty allows
+    /// every author
+    pub authors: Vec<String>,
+    /// Keep the selected builds as listed, without their stages, instead of
+    /// fetching the details of each
+    pub compact: bool,
+    pub correlation: Correlation,
+    /// Records the `filter_build` decision of every build looked at
+    pub trace: Option<&'trace FilterTrace>,
+}
+
+/// Which builds at the edges of the window are selected. Builds still running
+/// when the window closes at `window_start` never are; builds created before
+/// it opens at `window_end` are only selected when lenient. In the example
+/// below, strict selects builds 4569 and 4570, lenient 4568 as well.
+///
+/// ```text
+///                 window_end             window_start
+///                      |                       |
+///   4567  |-------|    |                       |       older than the window
+///   4568          |----+-----|                 |       created before it
+///   4569               |  |--------|           |       within it
+///   4570               |       |----------|    |       within it
+///   4571               |              |--------+--|    finished after it
+///   4572               |                       | |--|  newer than the window
+/// (past) --------------+-----------------------+------> (now)
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WindowMode {
+    /// Builds both created and finished within the window
+    Strict,
+    /// Builds finished within the window, however long before it they were
+    /// created
+    Lenient,
+}
+
+/// What to do with a build from a Drone build list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "decision", content = "reason", rename_all = "snake_case")]
+pub enum FilterState {
+    /// Stop walking the list; no older build can fall within the window
+    Break,
+    /// Skip the build
+    Continue(SkipReason),
+    /// Compare the build
+    Select,
+}
+
+/// Why `filter_build` skipped a build
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// Numbered above `build_range`
+    BuildRange,
+    /// Missing its created or finished timestamp
+    MissingTimestamp,
+    /// Outside the window, or straddling a boundary `WindowMode` doesn't let
+    /// builds cross
+    OutsideWindow,
+    /// Not the kind of build `mode` compares
+    Mode,
+    /// Its target or source branch doesn't match
+    Branch,
+    /// Still running, or killed
+    Status,
+    /// Not by one of `authors`
+    Author,
+}
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "failed to create trace file '{}': {}", "path.display()", source)]
+pub struct TraceError {
+    path: PathBuf,
+    source: io::Error,
+}
+
+/// One line of a `FilterTrace`
+#[derive(Serialize)]
+struct TraceRecord<'a> {
+    repo: String,
+    instance: DroneInstance,
+    build_number: u32,
+    event: DroneEvent,
+    status: DroneStatus,
+    created: DroneTime,
+    started: DroneTime,
+    finished: DroneTime,
+    #[serde(flatten)]
+    state: &'a FilterState,
+}
+
+/// Writes the `filter_build` decision of every build as JSON Lines, e.g.
+/// `{"decision":"continue","reason":"outside_window",...}`, for debugging
+/// why builds were or weren't compared. Shared by the walks of both Drone
+/// instances; failing to write a line only warns, since the trace is no
+/// part of the report.
+pub struct FilterTrace {
+    path: PathBuf,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl FilterTrace {
+    pub fn create(path: &Path) -> Result<Self, TraceError> {
+        let file = File::create(path).map_err(|source| TraceError {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Ok(FilterTrace {
+            path: path.to_path_buf(),
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    fn record(
+        &self,
+        drone_client: &DroneClient,
+        drone_build_list_item: &DroneBuildListItem,
+        state: &FilterState,
+    ) {
+        let record = TraceRecord {
+            repo: drone_client.repo().to_string(),
+            instance: drone_client.instance(),
+            build_number: drone_build_list_item.number,
+            event: drone_build_list_item.event,
+            status: drone_build_list_item.status,
+            created: drone_build_list_item.timestamps.created,
+            started: drone_build_list_item.timestamps.started,
+            finished: drone_build_list_item.timestamps.finished,
+            state,
+        };
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let written = serde_json::to_writer(&mut *writer, &record)
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(writer));
+        if let Err(error) = written {
+            warn!(
+                "Failed to write trace file '{}': {error}",
+                self.path.display()
+            );
+        }
+    }
+
+    fn flush(&self) {
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(error) = writer.flush() {
+            warn!(
+                "Failed to write trace file '{}': {error}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+/// Selects builds finished at or before `window_start` and, unless
+/// `WindowMode::Lenient`, created at or after `window_end`
+pub fn filter_build(
+    drone_build_list_item: &DroneBuildListItem,
+    config: &SelectConfig,
+) -> FilterState {
+    let window_start = config.window_start;
+    let window_end = config.window_end;
+    let build_number = drone_build_list_item.number;
+    if let Some(build_range) = &config.build_range {
+        // the list is newest first, so every build after one below the range
+        // is below it too
+        if build_number < *build_range.start() {
+            return FilterState::Break;
+        }
+        if build_number > *build_range.end() {
+            return FilterState::Continue(SkipReason::BuildRange);
+        }
+    }
+    let (Some(created), Some(finished)) = (
+        drone_build_list_item.timestamps.created.to_system_time(),
+        drone_build_list_item.timestamps.finished.to_system_time(),
+    ) else {
+        // without both timestamps the build can't be placed relative to the window
+        debug!("Build '{build_number}' is missing its created or finished timestamp");
+        return FilterState::Continue(SkipReason::MissingTimestamp);
+    };
+    // if build was created and finished outside window, unlikely any older builds will be within window, ignore and break
+    if finished < window_end && created < window_end {
+        return FilterState::Break;
+    }
+    // if build finished after window_start or, when strict, was created before
+    // window_end, ignore
+    let created_before_window = config.window_mode == WindowMode::Strict && created < window_end;
+    if finished > window_start || created_before_window {
+        return FilterState::Continue(SkipReason::OutsideWindow);
+    }
+
+    let mode_matches = match config.mode {
+        BuildMode::PullRequest => drone_build_list_item.event == DroneEvent::PullRequest,
+        BuildMode::Develop => {
+            drone_build_list_item.event == DroneEvent::Push
+                && drone_build_list_item.source == "develop"
+                && drone_build_list_item.target == "develop"
+        }
+        BuildMode::Tag => {
+            drone_build_list_item.event == DroneEvent::Tag
+                && drone_build_list_item
+                    .git_metadata
+                    .git_ref
+                    .starts_with("refs/tags/")
+        }
+    };
+    if !mode_matches {
+        return FilterState::Continue(SkipReason::Mode);
+    }
+    let branch_matches = |pattern: &Option<Regex>, branch: &str| {
+        pattern
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(branch))
+    };
+    if !branch_matches(&config.target_branch, &drone_build_list_item.target)
+        || !branch_matches(&config.source_branch, &drone_build_list_item.source)
+    {
+        return FilterState::Continue(SkipReason::Branch);
+    }
+    if drone_build_list_item.status == DroneStatus::Running
+        || drone_build_list_item.status == DroneStatus::Killed
+    {
+        return FilterState::Continue(SkipReason::Status);
+    }
+    if !config.authors.is_empty()
+        && !config.authors.contains(
+            &drone_build_list_item
+                .author_data
+                .author_login
+                .to_lowercase(),
+        )
+    {
+        return FilterState::Continue(SkipReason::Author);
+    }
+
+    FilterState::Select
+}
+
+/// Fetches the details of every build in `build_numbers` using at most
+/// `concurrency` requests at a time, returning them in the same order with
+/// duplicates fetched and returned once
+pub fn fetch_build_infos(
+    drone_client: &DroneClient,
+    build_numbers: &[u32],
+    concurrency: usize,
+    progress: &ProgressBar,
+) -> Result<Vec<DroneBuildInfo>, DroneError> {
+    let mut drone_build_infos =
+        drone_client.get_build_info_batch(build_numbers, concurrency, || progress.inc(1))?;
+    Ok(build_numbers
+        .iter()
+        .filter_map(|build_number| drone_build_infos.remove(build_number))
+        .collect())
+}
+
+/// Builds selected by `filter_build` from one Drone instance's build list,
+/// along with how many list pages were requested to find them
+pub struct SelectedBuilds {
+    pub builds: Vec<DroneBuildListItem>,
+    pub pages_fetched: usize,
+}
+
+/// Walks the build list of one Drone instance without fetching any details
+pub fn select_build_list(
+    drone_client: &DroneClient,
+    config: &SelectConfig,
+) -> Result<SelectedBuilds, DroneError> {
+    let mut drone_builds = drone_client
+        .get_builds_paginated()
+        .per_page(config.page_size)
+        .pagination(config.pagination);
+    if let Some(max_builds) = config.max_builds {
+        drone_builds = drone_builds.max_builds(max_builds);
+    }
+    if let Some(since_build) = config.since_build {
+        drone_builds = drone_builds.since_build(since_build);
+    }
+    let mut builds = Vec::new();
+    for drone_build_list_item in drone_builds.by_ref() {
+        let drone_build_list_item = drone_build_list_item?;
+        let state = filter_build(&drone_build_list_item, config);
+        if let Some(trace) = config.trace {
+            trace.record(drone_client, &drone_build_list_item, &state);
+        }
+        match state {
+            FilterState::Break => break,
+            FilterState::Continue(_) => continue,
+            FilterState::Select
+                if config
+                    .skip_builds
+                    .contains(&(drone_client.instance(), drone_build_list_item.number)) =>
+            {
+                continue
+            }
+            FilterState::Select => builds.push(drone_build_list_item),
+        }
+    }
+    if let Some(trace) = config.trace {
+        trace.flush();
+    }
+    if config.head_only {
+        retain_head_builds(&mut builds);
+    }
+    Ok(SelectedBuilds {
+        builds,
+        pages_fetched: drone_builds.pages_fetched(),
+    })
+}
+
+/// Drops every build but the highest-numbered, i.e. the last rerun, of each
+/// git sha
+fn retain_head_builds(builds: &mut Vec<DroneBuildListItem>) {
+    let mut head_build_numbers: HashMap<String, u32> = HashMap::new();
+    for build in builds.iter() {
+        let head_build_number = head_build_numbers
+            .entry(build.git_metadata.git_sha.clone())
+            .or_insert(build.number);
+        *head_build_number = (*head_build_number).max(build.number);
+    }
+    builds.retain(|build| head_build_numbers[&build.git_metadata.git_sha] == build.number);
+}
+
+/// A spinner with a running count of the builds of `instance` fetched, as
+/// the total isn't known while paging
+fn instance_progress(multi_progress: &MultiProgress, instance: DroneInstance) -> ProgressBar {
+    let progress = multi_progress.add(ProgressBar::new_spinner());
+    progress.set_style(
+        ProgressStyle::with_template("{spinner} {msg}: {pos} builds fetched")
+            .expect("progress template is valid"),
+    );
+    progress.set_message(instance.to_string());
+    progress.enable_steady_tick(Duration::from_millis(100));
+    progress
+}
+
+/// Selects the builds of both instances; without `drone2_client` every
+/// commit's Drone2 builds are left empty
+pub fn drone_build_map(
+    drone1_client: &DroneClient,
+    drone2_client: Option<&DroneClient>,
+    config: &SelectConfig,
+) -> Result<CommitBuildMap, DroneError> {
+    let mut commit_build_map = CommitBuildMap::new();
+    drone_build_map_chunks(drone1_client, drone2_client, config, usize::MAX, |chunk| {
+        commit_build_map.extend(chunk);
+        Ok::<_, DroneError>(())
+    })?;
+    Ok(commit_build_map)
+}
+
+/// Like `drone_build_map`, but hands the builds to `on_chunk`
+/// `commits_per_chunk` commits at a time, as soon as the details of each
+/// chunk are fetched, so a report can be written as it goes rather than
+/// once every build is in memory. Both build lists are walked first, and the
+/// commits are chunked going by their earliest Drone1 build, close to the
+/// order of the report's rows, see `csv::build_rows`.
+pub fn drone_build_map_chunks<E: From<DroneError>>(
+    drone1_client: &DroneClient,
+    drone2_client: Option<&DroneClient>,
+    config: &SelectConfig,
+    commits_per_chunk: usize,
+    mut on_chunk: impl FnMut(CommitBuildMap) -> Result<(), E>,
+) -> Result<(), E> {
+    // both spinners draw to stderr, which hides them when it's not a TTY
+    let multi_progress = if config.progress {
+        MultiProgress::new()
+    } else {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    };
+    let drone1_progress = instance_progress(&multi_progress, DroneInstance::Drone1);
+    let drone2_progress =
+        drone2_client.map(|_| instance_progress(&multi_progress, DroneInstance::Drone2));
+
+    // the instances are walked independently, each on its own thread
+    let (drone1_selected, drone2_selected) = thread::scope(|scope| {
+        let drone1_selected = scope.spawn(|| select_build_list(drone1_client, config));
+        let drone2_selected = drone2_client
+            .map(|drone2_client| scope.spawn(|| select_build_list(drone2_client, config)));
+        (
+            drone1_selected.join().unwrap(),
+            drone2_selected
+                .map(|drone2_selected| drone2_selected.join().unwrap())
+                .transpose(),
+        )
+    });
+    let listed = listed_build_map(
+        drone1_selected?.builds,
+        drone2_selected?.map_or_else(Vec::new, |selected| selected.builds),
+        config.correlation,
+    );
+    let mut keys: Vec<&String> = listed.keys().collect();
+    keys.sort_by_cached_key(|key| chunk_order(&listed[*key]));
+    if !config.compact {
+        for (drone_client, builds) in [
+            (
+                Some(drone1_client),
+                listed.values().map(|builds| builds.0.len()).sum::<usize>(),
+            ),
+            (
+                drone2_client,
+                listed.values().map(|builds| builds.1.len()).sum::<usize>(),
+            ),
+        ] {
+            if let Some(drone_client) = drone_client {
+                info!(
+                    "Fetching details of {} builds from '{}'",
+                    builds,
+                    drone_client.url()
+                );
+            }
+        }
+    }
+
+    for chunk_keys in keys.chunks(commits_per_chunk.max(1)) {
+        let chunk: CommitBuildMap = chunk_keys
+            .iter()
+            .map(|key| ((*key).clone(), listed[*key].clone()))
+            .collect();
+        let chunk = if config.compact {
+            // compact reports take everything from the build list
+            chunk
+        } else {
+            fetch_chunk(
+                drone1_client,
+                drone2_client,
+                &chunk,
+                config,
+                &drone1_progress,
+                drone2_progress.as_ref(),
+            )?
+        };
+        on_chunk(chunk)?;
+    }
+    drone1_progress.finish_and_clear();
+    if let Some(drone2_progress) = drone2_progress {
+        drone2_progress.finish_and_clear();
+    }
+    Ok(())
+}
+
+/// The listed builds of both instances grouped by `correlation`, without
+/// their details
+fn listed_build_map(
+    drone1_builds: Vec<DroneBuildListItem>,
+    drone2_builds: Vec<DroneBuildListItem>,
+    correlation: Correlation,
+) -> CommitBuildMap {
+    let mut listed = CommitBuildMap::new();
+    let drone1_builds = drone1_builds
+        .into_iter()
+        .map(DroneBuildInfo::from)
+        .collect();
+    for (key, drone_build_infos) in group_builds(drone1_builds, correlation) {
+        listed.entry(key).or_default().0 = drone_build_infos;
+    }
+    let drone2_builds = drone2_builds
+        .into_iter()
+        .map(DroneBuildInfo::from)
+        .collect();
+    for (key, drone_build_infos) in group_builds(drone2_builds, correlation) {
+        listed.entry(key).or_default().1 = drone_build_infos;
+    }
+    listed
+}
+
+/// Where the rows of a commit's builds land in the report, going by its
+/// earliest Drone1 build; commits without one produce no rows
+fn chunk_order(
+    (drone1_builds, _): &(Vec<DroneBuildInfo>, Vec<DroneBuildInfo>),
+) -> Option<(Option<u64>, String, u32)> {
+    drone1_builds
+        .iter()
+        .map(|drone_build_info| {
+            (
+                drone_build_info
+                    .get_pr_number()
+                    .and_then(|pr_number| pr_number.parse().ok()),
+                drone_build_info.build_info.git_metadata.git_sha.clone(),
+                drone_build_info.build_info.number,
+            )
+        })
+        .min()
+}
+
+/// Fetches the details of the listed builds of `chunk` from both instances
+/// at once
+fn fetch_chunk(
+    drone1_client: &DroneClient,
+    drone2_client: Option<&DroneClient>,
+    chunk: &CommitBuildMap,
+    config: &SelectConfig,
+    drone1_progress: &ProgressBar,
+    drone2_progress: Option<&ProgressBar>,
+) -> Result<CommitBuildMap, DroneError> {
+    let build_numbers = |builds: &[DroneBuildInfo]| -> Vec<u32> {
+        builds
+            .iter()
+            .map(|drone_build_info| drone_build_info.build_info.number)
+            .collect()
+    };
+    let drone1_build_numbers: Vec<u32> = chunk
+        .values()
+        .flat_map(|(drone1_builds, _)| build_numbers(drone1_builds))
+        .collect();
+    let drone2_build_numbers: Vec<u32> = chunk
+        .values()
+        .flat_map(|(_, drone2_builds)| build_numbers(drone2_builds))
+        .collect();
+    let (drone1_builds, drone2_builds) = thread::scope(|scope| {
+        let drone2_builds =
+            drone2_client
+                .zip(drone2_progress)
+                .map(|(drone2_client, drone2_progress)| {
+                    scope.spawn(|| {
+                        fetch_build_infos(
+                            drone2_client,
+                            &drone2_build_numbers,
+                            config.concurrency,
+                            drone2_progress,
+                        )
+                    })
+                });
+        (
+            fetch_build_infos(
+                drone1_client,
+                &drone1_build_numbers,
+                config.concurrency,
+                drone1_progress,
+            ),
+            drone2_builds.map_or(Ok(Vec::new()), |drone2_builds| {
+                drone2_builds.join().unwrap()
+            }),
+        )
+    });
+
+    let mut commit_build_map = CommitBuildMap::new();
+    for (key, drone_build_infos) in group_builds(drone1_builds?, config.correlation) {
+        commit_build_map.entry(key).or_default().0 = drone_build_infos;
+    }
+    for (key, drone_build_infos) in group_builds(drone2_builds?, config.correlation) {
+        commit_build_map.entry(key).or_default().1 = drone_build_infos;
+    }
+    Ok(commit_build_map)
+}
+
+/// Groups builds by their `correlation` key, keeping the builds of each key
+/// in the order given
+fn group_builds(
+    drone_build_infos: Vec<DroneBuildInfo>,
+    correlation: Correlation,
+) -> HashMap<String, Vec<DroneBuildInfo>> {
+    let mut grouped_builds: HashMap<String, Vec<DroneBuildInfo>> = HashMap::new();
+    for drone_build_info in drone_build_infos {
+        grouped_builds
+            .entry(correlation.key(&drone_build_info))
+            .or_default()
+            .push(drone_build_info);
+    }
+    grouped_builds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+    use reqwest::Url;
+    use std::time::UNIX_EPOCH;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    /// Selects pull request builds created and finished between 1000 and
+    /// 2000 seconds after the epoch
+    fn config() -> SelectConfig<'static> {
+        SelectConfig {
+            window_start: at(2000),
+            window_end: at(1000),
+            window_mode: WindowMode::Strict,
+            mode: BuildMode::PullRequest,
+            concurrency: 1,
+            max_builds: None,
+            since_build: None,
+            build_range: None,
+            head_only: false,
+            skip_builds: HashSet::new(),
+            page_size: 100,
+            pagination: Pagination::Page,
+            target_branch: None,
+            source_branch: None,
+            progress: false,
+            authors: Vec::new(),
+            compact: false,
+            correlation: Correlation::Sha,
+            trace: None,
+        }
+    }
+
+    fn build(number: u32, created: i64, finished: i64) -> DroneBuildListItem {
+        serde_json::from_value(build_json(number, created, finished)).unwrap()
+    }
+
+    fn build_json(number: u32, created: i64, finished: i64) -> serde_json::Value {
+        serde_json::json!({
+            "id": number, "repo_id": 1, "trigger": "@hook", "number": number,
+            "status": "success", "event": "pull_request", "action": "sync",
+            "link": format!("https://github.com/o/r/pull/{number}.diff"),
+            "timestamp": 0, "message": "", "before": "", "after": format!("sha{number}"),
+            "ref": format!("refs/pull/{number}/head"), "source_repo": "o/r",
+            "source": "feature", "target": "develop", "author_login": "octocat",
+            "author_name": "", "author_email": "", "author_avatar": "https://x/",
+            "sender": "octocat", "started": created, "finished": finished,
+            "created": created, "updated": finished, "version": 1,
+        })
+    }
+
+    /// A build of `git_sha` within the window of `config`
+    fn build_of(number: u32, git_sha: &str) -> serde_json::Value {
+        let mut build = build_json(number, 1500, 1600);
+        build["after"] = git_sha.into();
+        build
+    }
+
+    /// A Drone serving `builds` as its build list, newest first, and each
+    /// build's details with a single stage
+    fn drone(instance: DroneInstance, builds: &[serde_json::Value]) -> (MockServer, DroneClient) {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/api/repos/o/r/builds").query_param("page", "1");
+            then.json_body(serde_json::Value::from(builds.to_vec()));
+        });
+        server.mock(|when, then| {
+            when.path("/api/repos/o/r/builds").query_param("page", "2");
+            then.json_body(serde_json::json!([]));
+        });
+        for build in builds {
+            let mut build_info = build.clone();
+            build_info["stages"] = serde_json::json!([{
+                "id": 1, "repo_id": 1, "build_id": build["id"], "number": 1,
+                "name": "build-pull-request", "status": "success", "errignore": false,
+                "exit_code": 0, "machine": null, "os": "linux", "arch": "amd64",
+                "started": 1500, "stopped": 1600, "created": 1500, "updated": 1600,
+                "version": 1, "on_success": true, "on_failure": false, "steps": [],
+            }]);
+            server.mock(|when, then| {
+                when.path(format!("/api/repos/o/r/builds/{}", build["number"]));
+                then.json_body(build_info);
+            });
+        }
+        let drone_client = DroneClient::builder(
+            instance,
+            Url::parse(&server.base_url()).unwrap(),
+            "o/r".parse().unwrap(),
+            "token".to_string(),
+        )
+        .build()
+        .unwrap();
+        (server, drone_client)
+    }
+
+    /// The build numbers of each instance by key, sorted
+    fn build_numbers(commit_build_map: &CommitBuildMap) -> Vec<(String, Vec<u32>, Vec<u32>)> {
+        let numbers = |builds: &[DroneBuildInfo]| -> Vec<u32> {
+            builds
+                .iter()
+                .map(|drone_build_info| drone_build_info.build_info.number)
+                .collect()
+        };
+        let mut build_numbers: Vec<_> = commit_build_map
+            .iter()
+            .map(|(key, (drone1_builds, drone2_builds))| {
+                (key.clone(), numbers(drone1_builds), numbers(drone2_builds))
+            })
+            .collect();
+        build_numbers.sort();
+        build_numbers
+    }
+
+    #[test]
+    fn builds_without_timestamps_are_skipped() {
+        let config = config();
+        for (created, finished) in [(1500, 0), (0, 1500), (1500, -1), (-1500, 1600), (0, 0)] {
+            assert_eq!(
+                filter_build(&build(1, created, finished), &config),
+                FilterState::Continue(SkipReason::MissingTimestamp),
+                "created {created}, finished {finished}"
+            );
+        }
+        assert_eq!(
+            filter_build(&build(1, 1500, 1600), &config),
+            FilterState::Select
+        );
+    }
+
+    #[test]
+    fn build_map_keeps_each_instance_on_its_own_side() {
+        let (_drone1_server, drone1_client) = drone(
+            DroneInstance::Drone1,
+            &[build_of(3, "ccc"), build_of(2, "bbb"), build_of(1, "aaa")],
+        );
+        let (_drone2_server, drone2_client) = drone(
+            DroneInstance::Drone2,
+            &[
+                build_of(13, "ccc"),
+                build_of(12, "ddd"),
+                build_of(11, "aaa"),
+            ],
+        );
+
+        let commit_build_map =
+            drone_build_map(&drone1_client, Some(&drone2_client), &config()).unwrap();
+
+        assert_eq!(
+            build_numbers(&commit_build_map),
+            [
+                ("aaa".to_string(), vec![1], vec![11]),
+                ("bbb".to_string(), vec![2], vec![]),
+                ("ccc".to_string(), vec![3], vec![13]),
+                ("ddd".to_string(), vec![], vec![12]),
+            ]
+        );
+        // the details fetched land with the build they belong to
+        for (git_sha, (drone1_builds, drone2_builds)) in &commit_build_map {
+            for drone_build_info in drone1_builds.iter().chain(drone2_builds) {
+                assert_eq!(&drone_build_info.build_info.git_metadata.git_sha, git_sha);
+                assert_eq!(drone_build_info.stages.len(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn build_map_without_drone2_leaves_its_side_empty() {
+        let (_drone1_server, drone1_client) = drone(
+            DroneInstance::Drone1,
+            &[build_of(2, "bbb"), build_of(1, "aaa")],
+        );
+
+        let commit_build_map = drone_build_map(&drone1_client, None, &config()).unwrap();
+
+        assert_eq!(
+            build_numbers(&commit_build_map),
+            [
+                ("aaa".to_string(), vec![1], vec![]),
+                ("bbb".to_string(), vec![2], vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn build_map_is_the_same_at_any_concurrency() {
+        for mode in [BuildMode::PullRequest, BuildMode::Develop] {
+            let builds = |numbers: std::ops::RangeInclusive<u32>| -> Vec<serde_json::Value> {
+                numbers
+                    .rev()
+                    .map(|number| {
+                        let mut build = build_of(number, &format!("sha{}", number % 7));
+                        if mode == BuildMode::Develop {
+                            build["event"] = "push".into();
+                            build["source"] = "develop".into();
+                            build["target"] = "develop".into();
+                        }
+                        build
+                    })
+                    .collect()
+            };
+            let (_drone1_server, drone1_client) = drone(DroneInstance::Drone1, &builds(1..=20));
+            let (_drone2_server, drone2_client) = drone(DroneInstance::Drone2, &builds(31..=45));
+            let build_map = |concurrency| {
+                let config = SelectConfig {
+                    mode,
+                    concurrency,
+                    ..config()
+                };
+                let commit_build_map =
+                    drone_build_map(&drone1_client, Some(&drone2_client), &config).unwrap();
+                serde_json::to_value(commit_build_map).unwrap()
+            };
+
+            let serial = build_map(1);
+            assert_eq!(serial.as_object().unwrap().len(), 7, "{mode:?}");
+            for concurrency in [2, 8, 32] {
+                assert_eq!(build_map(concurrency), serial, "{mode:?} at {concurrency}");
+            }
+        }
+    }
+
+    #[test]
+    fn strict_windows_select_builds_created_and_finished_inside() {
+        let config = config();
+        let state = |created, finished| filter_build(&build(1, created, finished), &config);
+        // the window runs from 1000 to 2000, both ends included
+        assert_eq!(state(1000, 2000), FilterState::Select);
+        assert_eq!(state(1500, 1600), FilterState::Select);
+        // straddling the end of the window, i.e. created before it
+        assert_eq!(
+            state(999, 1500),
+            FilterState::Continue(SkipReason::OutsideWindow)
+        );
+        // straddling its start, i.e. finished after it or still unfinished
+        // when it closed
+        assert_eq!(
+            state(1500, 2001),
+            FilterState::Continue(SkipReason::OutsideWindow)
+        );
+        assert_eq!(
+            state(999, 2001),
+            FilterState::Continue(SkipReason::OutsideWindow)
+        );
+        // newer than the window altogether
+        assert_eq!(
+            state(2100, 2200),
+            FilterState::Continue(SkipReason::OutsideWindow)
+        );
+        // older than the window, and so is every build listed after it
+        assert_eq!(state(500, 999), FilterState::Break);
+    }
+
+    #[test]
+    fn lenient_windows_select_builds_finished_inside() {
+        let config = SelectConfig {
+            window_mode: WindowMode::Lenient,
+            ..config()
+        };
+        let state = |created, finished| filter_build(&build(1, created, finished), &config);
+        assert_eq!(state(1000, 2000), FilterState::Select);
+        assert_eq!(state(1500, 1600), FilterState::Select);
+        // straddling the end of the window, however far back
+        assert_eq!(state(999, 1000), FilterState::Select);
+        assert_eq!(state(10, 1500), FilterState::Select);
+        // straddling its start is still too late
+        assert_eq!(
+            state(1500, 2001),
+            FilterState::Continue(SkipReason::OutsideWindow)
+        );
+        assert_eq!(
+            state(999, 2001),
+            FilterState::Continue(SkipReason::OutsideWindow)
+        );
+        assert_eq!(
+            state(2100, 2200),
+            FilterState::Continue(SkipReason::OutsideWindow)
+        );
+        assert_eq!(state(500, 999), FilterState::Break);
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+
+use crate::csv::Row;
+
+/// Distribution of one elapsed-time column, in seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ElapsedStats {
+    pub count: usize,
+    pub mean: f64,
+    pub median: i64,
+    pub p90: i64,
+    pub p99: i64,
+}
+
+impl ElapsedStats {
+    /// `None` when there are no values to summarize
+    pub fn from_values(mut values: Vec<i64>) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_unstable();
+        let count = values.len();
+        let mean = values.iter().map(|value| *value as f64).sum::<f64>() / count as f64;
+        Some(ElapsedStats {
+            count,
+            mean,
+            median: percentile(&values, 50),
+            p90: percentile(&values, 90),
+            p99: percentile(&values, 99),
+        })
+    }
+}
+
+/// Nearest-rank percentile of already sorted, non-empty `values`
+fn percentile(values: &[i64], percentile: usize) -> i64 {
+    let rank = (percentile * values.len()).div_ceil(100).max(1);
+    values[rank - 1]
+}
+
+impl fmt::Display for ElapsedStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "count={} mean={:.1}s median={}s p90={}s p99={}s",
+            self.count, self.mean, self.median, self.p90, self.p99
+        )
+    }
+}
+
+/// Aggregate view over all rows of a report
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub rows: usize,
+    pub drone1_unit_test_elapsed_time: Option<ElapsedStats>,
+    pub drone2_total_elapsed_time: Option<ElapsedStats>,
+    /// Fraction of rows where the await step did not finish within the
+    /// await threshold of the unit test step starting
+    pub await_threshold_breach_ratio: Option<f64>,
+}
+
+pub fn summarize(rows: &[Row]) -> Summary {
+    // rows of skipped unit test steps have no timings to summarize
+    let compared: Vec<bool> = rows
+        .iter()
+        .filter_map(|row| row.await_within_three_minutes_of_unit_test_start)
+        .collect();
+    let breaches = compared.iter().filter(|within| !**within).count();
+    Summary {
+        rows: rows.len(),
+        drone1_unit_test_elapsed_time: ElapsedStats::from_values(
+            rows.iter()
+                .filter_map(|row| row.drone1_unit_test_elapsed_time)
+                .collect(),
+        ),
+        drone2_total_elapsed_time: ElapsedStats::from_values(
+            rows.iter()
+                .filter_map(|row| row.drone2_total_elapsed_time)
+                .collect(),
+        ),
+        await_threshold_breach_ratio: (!compared.is_empty())
+            .then(|| breaches as f64 / compared.len() as f64),
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rows: {}", self.rows)?;
+        let columns = [
+            (
+                "drone1_unit_test_elapsed_time",
+                &self.drone1_unit_test_elapsed_time,
+            ),
+            ("drone2_total_elapsed_time", &self.drone2_total_elapsed_time),
+        ];
+        for (name, stats) in columns {
+            match stats {
+                Some(stats) => writeln!(f, "{name}: {stats}")?,
+                None => writeln!(f, "{name}: no data")?,
+            }
+        }
+        match self.await_threshold_breach_ratio {
+            Some(ratio) => write!(f, "await threshold breached: {:.1}%", ratio * 100.0),
+            None => write!(f, "await threshold breached: no data"),
+        }
+    }
+}
@@ -0,0 +1,370 @@
+//! `DroneClient` against a fake Drone server serving the builds of
+//! `tests/fixtures`
+
+use cuddly_robot::drone::{
+    DroneBuildListItem, DroneClient, DroneError, DroneInstance, DroneStage, DroneStatus, DroneStep,
+    RetryPolicy, MAX_PAGE_SIZE,
+};
+use httpmock::prelude::*;
+use reqwest::Url;
+use std::time::{Duration, Instant};
+
+const REPO: &str = "BitGo/wallet-platform";
+const TOKEN: &str = "token";
+
+fn fixture(name: &str) -> String {
+    let path = format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"));
+    std::fs::read_to_string(&path).unwrap_or_else(|error| panic!("{path}: {error}"))
+}
+
+fn client(instance: DroneInstance, server: &MockServer) -> DroneClient {
+    DroneClient::builder(
+        instance,
+        Url::parse(&server.base_url()).unwrap(),
+        REPO.parse().unwrap(),
+        TOKEN.to_string(),
+    )
+    .build()
+    .unwrap()
+}
+
+/// Serves `body` for page `page` of the build list, `per_page` builds each
+fn mock_page<'server>(
+    server: &'server MockServer,
+    page: usize,
+    per_page: usize,
+    body: &str,
+) -> httpmock::Mock<'server> {
+    server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/api/repos/{REPO}/builds"))
+            .query_param("page", page.to_string())
+            .query_param("per_page", per_page.to_string())
+            .header("authorization", format!("Bearer {TOKEN}"));
+        then.status(200)
+            .header("content-type", "application/json")
+            .body(body);
+    })
+}
+
+fn mock_build<'server>(
+    server: &'server MockServer,
+    build_number: u32,
+    body: &str,
+) -> httpmock::Mock<'server> {
+    server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/api/repos/{REPO}/builds/{build_number}"));
+        then.status(200)
+            .header("content-type", "application/json")
+            .body(body);
+    })
+}
+
+#[test]
+fn get_builds_paginated_walks_every_page() {
+    let server = MockServer::start();
+    let pages = [
+        mock_page(&server, 1, 2, &fixture("builds_page1.json")),
+        mock_page(&server, 2, 2, &fixture("builds_page2.json")),
+        mock_page(&server, 3, 2, "[]"),
+    ];
+
+    let drone_client = client(DroneInstance::Drone1, &server);
+    let builds: Vec<DroneBuildListItem> = drone_client
+        .get_builds_paginated()
+        .per_page(2)
+        .collect::<Result<_, _>>()
+        .unwrap();
+
+    for page in pages {
+        page.assert();
+    }
+    let build_numbers: Vec<u32> = builds.iter().map(|build| build.number).collect();
+    assert_eq!(build_numbers, [12, 11, 10]);
+    let build = &builds[0];
+    assert_eq!(build.id, 1012);
+    assert_eq!(build.status, DroneStatus::Success);
+    assert_eq!(
+        build.git_metadata.git_sha,
+        "c0ffee12aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"
+    );
+    assert_eq!(build.git_metadata.git_ref, "refs/pull/312/head");
+    assert_eq!(
+        build.link.as_str(),
+        "https://github.com/BitGo/wallet-platform/pull/312.diff"
+    );
+    assert_eq!(build.author_data.author_login, "octocat");
+}
+
+#[test]
+fn get_builds_paginated_stops_at_max_builds() {
+    let server = MockServer::start();
+    let first_page = mock_page(&server, 1, 2, &fixture("builds_page1.json"));
+    let second_page = mock_page(&server, 2, 2, &fixture("builds_page2.json"));
+
+    let drone_client = client(DroneInstance::Drone1, &server);
+    let builds: Vec<DroneBuildListItem> = drone_client
+        .get_builds_paginated()
+        .per_page(2)
+        .max_builds(2)
+        .collect::<Result<_, _>>()
+        .unwrap();
+
+    assert_eq!(builds.len(), 2);
+    first_page.assert();
+    second_page.assert_hits(0);
+}
+
+#[test]
+fn get_build_info_parses_drone1_stages() {
+    let server = MockServer::start();
+    let build = mock_build(&server, 12, &fixture("drone1_build.json"));
+
+    let drone_build_info = client(DroneInstance::Drone1, &server)
+        .get_build_info(12)
+        .unwrap();
+
+    build.assert();
+    assert_eq!(drone_build_info.build_info.number, 12);
+    assert_eq!(drone_build_info.get_pr_number().as_deref(), Some("312"));
+    assert_eq!(drone_build_info.elapsed_time(), Some(600));
+    let [DroneStage::Drone1Stage(stage)] = &drone_build_info.stages[..] else {
+        panic!(
+            "expected one Drone1 stage, got {:?}",
+            drone_build_info.stages
+        );
+    };
+    assert_eq!(stage.name, "build-pull-request");
+    assert_eq!(stage.stage_type, None);
+    let unit_tests = drone_build_info
+        .get_stage("build-pull-request")
+        .and_then(|stage| stage.get_step("run-wallet-platform-unit-tests"))
+        .unwrap();
+    assert!(matches!(unit_tests, DroneStep::Drone1Step(_)));
+    assert_eq!(unit_tests.get_status(), DroneStatus::Success);
+    assert_eq!(unit_tests.elapsed_time(), Some(300));
+}
+
+#[test]
+fn get_build_info_parses_drone2_stages() {
+    let server = MockServer::start();
+    let build = mock_build(&server, 12, &fixture("drone2_build.json"));
+
+    let drone_build_info = client(DroneInstance::Drone2, &server)
+        .get_build_info(12)
+        .unwrap();
+
+    build.assert();
+    let [DroneStage::Drone2Stage(unit_stage), DroneStage::Drone2Stage(system_stage)] =
+        &drone_build_info.stages[..]
+    else {
+        panic!(
+            "expected two Drone2 stages, got {:?}",
+            drone_build_info.stages
+        );
+    };
+    assert_eq!(unit_stage.kind, "pipeline");
+    assert_eq!(unit_stage.stage_type, "kubernetes");
+    assert_eq!(unit_stage.depends_on, None);
+    assert_eq!(
+        system_stage.depends_on.as_deref(),
+        Some(&["wallet-platform".to_string()][..])
+    );
+    let DroneStep::Drone2Step(step) = &system_stage.drone_stage.steps[1] else {
+        panic!("expected a Drone2 step");
+    };
+    assert_eq!(step.drone_step.name, "system-tests");
+    assert_eq!(step.image, "node:18");
+    assert_eq!(step.depends_on.as_deref(), Some(&["clone".to_string()][..]));
+}
+
+#[test]
+fn get_build_info_settles_drone2_stages_of_drone1_builds() {
+    let server = MockServer::start();
+    mock_build(&server, 12, &fixture("drone2_build.json"));
+
+    let drone_build_info = client(DroneInstance::Drone1, &server)
+        .get_build_info(12)
+        .unwrap();
+
+    for stage in &drone_build_info.stages {
+        let DroneStage::Drone1Stage(stage) = stage else {
+            panic!("expected Drone1 stages, got {stage:?}");
+        };
+        assert_eq!(stage.stage_type.as_deref(), Some("kubernetes"));
+        assert!(stage
+            .steps
+            .iter()
+            .all(|step| matches!(step, DroneStep::Drone1Step(_))));
+    }
+}
+
+#[test]
+fn get_build_info_rejects_drone2_stages_with_drone1_steps() {
+    let mut build: serde_json::Value = serde_json::from_str(&fixture("drone2_build.json")).unwrap();
+    build["stages"][1]["steps"][1]
+        .as_object_mut()
+        .unwrap()
+        .remove("image");
+    let server = MockServer::start();
+    mock_build(&server, 12, &build.to_string());
+
+    let error = client(DroneInstance::Drone2, &server)
+        .get_build_info(12)
+        .unwrap_err();
+
+    assert!(
+        matches!(
+            error,
+            DroneError::UnexpectedShape {
+                instance: DroneInstance::Drone2,
+                ..
+            }
+        ),
+        "{error}"
+    );
+}
+
+fn gzip(body: &str) -> Vec<u8> {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(body.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Serves build 12 gzipped to clients that accept it
+fn mock_gzipped_build(server: &MockServer) -> httpmock::Mock<'_> {
+    server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/api/repos/{REPO}/builds/12"))
+            .header("accept-encoding", "gzip, deflate");
+        then.status(200)
+            .header("content-type", "application/json")
+            .header("content-encoding", "gzip")
+            .body(gzip(&fixture("drone1_build.json")));
+    })
+}
+
+#[test]
+fn get_build_info_decompresses_gzipped_responses() {
+    let server = MockServer::start();
+    let build = mock_gzipped_build(&server);
+
+    let drone_build_info = client(DroneInstance::Drone1, &server)
+        .get_build_info(12)
+        .unwrap();
+
+    build.assert();
+    assert_eq!(drone_build_info.build_info.number, 12);
+    assert_eq!(drone_build_info.stages.len(), 1);
+}
+
+#[test]
+fn get_build_info_without_compression_does_not_ask_for_it() {
+    let server = MockServer::start();
+    let gzipped_build = mock_gzipped_build(&server);
+    let plain_build = mock_build(&server, 12, &fixture("drone1_build.json"));
+
+    let drone_build_info = DroneClient::builder(
+        DroneInstance::Drone1,
+        Url::parse(&server.base_url()).unwrap(),
+        REPO.parse().unwrap(),
+        TOKEN.to_string(),
+    )
+    .no_compression()
+    .build()
+    .unwrap()
+    .get_build_info(12)
+    .unwrap();
+
+    gzipped_build.assert_hits(0);
+    plain_build.assert();
+    assert_eq!(drone_build_info.build_info.number, 12);
+}
+
+#[test]
+fn get_builds_for_commit_finds_the_original_build_pages_behind_its_rerun() {
+    // build 12 restarted build 10, so the rerun tops the list and the
+    // original build is on the next page
+    let rerun_page: serde_json::Value =
+        serde_json::from_str(&fixture("builds_page1.json")).unwrap();
+    let git_sha = rerun_page[0]["after"].as_str().unwrap();
+    let mut original_page: serde_json::Value =
+        serde_json::from_str(&fixture("builds_page2.json")).unwrap();
+    original_page[0]["after"] = git_sha.into();
+    let server = MockServer::start();
+    let pages = [
+        mock_page(&server, 1, MAX_PAGE_SIZE, &rerun_page.to_string()),
+        mock_page(&server, 2, MAX_PAGE_SIZE, &original_page.to_string()),
+        mock_page(&server, 3, MAX_PAGE_SIZE, "[]"),
+    ];
+
+    let builds = client(DroneInstance::Drone1, &server)
+        .get_builds_for_commit(git_sha, 1000)
+        .unwrap();
+
+    for page in pages {
+        page.assert();
+    }
+    let build_numbers: Vec<u32> = builds.iter().map(|build| build.number).collect();
+    assert_eq!(build_numbers, [12, 10]);
+}
+
+#[test]
+fn get_builds_for_commit_stops_at_max_builds() {
+    let server = MockServer::start();
+    let first_page = mock_page(&server, 1, MAX_PAGE_SIZE, &fixture("builds_page1.json"));
+    let second_page = mock_page(&server, 2, MAX_PAGE_SIZE, &fixture("builds_page2.json"));
+
+    let builds = client(DroneInstance::Drone1, &server)
+        .get_builds_for_commit("c0ffee10", 2)
+        .unwrap();
+
+    assert!(builds.is_empty());
+    first_page.assert();
+    second_page.assert_hits(0);
+}
+
+#[test]
+fn get_build_info_waits_out_oversized_retry_after_only_up_to_the_max_delay() {
+    let server = MockServer::start();
+    let rate_limited = server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/api/repos/{REPO}/builds/12"));
+        then.status(429).header("retry-after", "86400");
+    });
+    let drone_client = DroneClient::builder(
+        DroneInstance::Drone1,
+        Url::parse(&server.base_url()).unwrap(),
+        REPO.parse().unwrap(),
+        TOKEN.to_string(),
+    )
+    .retry_policy(RetryPolicy {
+        max_retries: 1,
+        max_delay: Duration::from_millis(50),
+        ..Default::default()
+    })
+    .build()
+    .unwrap();
+
+    let started = Instant::now();
+    let error = drone_client.get_build_info(12).unwrap_err();
+
+    assert!(
+        started.elapsed() < Duration::from_secs(10),
+        "waited {:?}",
+        started.elapsed()
+    );
+    assert!(
+        matches!(
+            error,
+            DroneError::RateLimited {
+                retry_after: Some(retry_after),
+                ..
+            } if retry_after == Duration::from_secs(86400)
+        ),
+        "{error}"
+    );
+    rate_limited.assert_hits(2);
+}